@@ -0,0 +1,118 @@
+//! Running `wallpaper-rs supervise` at login, which sets up the wallpapers of the config and
+//! keeps them alive without showing the UI.
+
+use std::io;
+
+/// Command line started at login.
+fn login_command() -> io::Result<String> {
+    let exe = std::env::current_exe()?;
+    Ok(format!("\"{}\" supervise", exe.display()))
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::io;
+    use std::ptr::null_mut;
+
+    use winapi::shared::minwindef::HKEY;
+    use winapi::shared::winerror::ERROR_SUCCESS;
+    use winapi::um::winreg::{RegCloseKey, RegOpenKeyExW, HKEY_CURRENT_USER};
+    use winapi::um::winnt::{KEY_QUERY_VALUE, KEY_SET_VALUE, REG_SZ};
+
+    use crate::backend::windows::to_wide;
+
+    const RUN_KEY: &str = "Software\\Microsoft\\Windows\\CurrentVersion\\Run";
+    const VALUE_NAME: &str = "wallpaper-rs";
+
+    fn check(status: i32) -> io::Result<()> {
+        if status as u32 == ERROR_SUCCESS {
+            Ok(())
+        } else {
+            Err(io::Error::from_raw_os_error(status))
+        }
+    }
+
+    /// Opens the per-user `Run` key, closing it once `f` returns.
+    fn with_run_key<T>(access: u32, f: impl FnOnce(HKEY) -> io::Result<T>) -> io::Result<T> {
+        let mut key: HKEY = null_mut();
+        check(unsafe { RegOpenKeyExW(HKEY_CURRENT_USER, to_wide(RUN_KEY).as_ptr(), 0, access, &mut key) })?;
+        let result = f(key);
+        unsafe { RegCloseKey(key) };
+        result
+    }
+
+    pub fn is_enabled() -> bool {
+        use winapi::um::winreg::RegQueryValueExW;
+
+        with_run_key(KEY_QUERY_VALUE, |key| check(unsafe {
+            RegQueryValueExW(key, to_wide(VALUE_NAME).as_ptr(), null_mut(), null_mut(), null_mut(), null_mut())
+        })).is_ok()
+    }
+
+    pub fn enable(command: &str) -> io::Result<()> {
+        use winapi::um::winreg::RegSetValueExW;
+
+        let data = to_wide(command);
+        with_run_key(KEY_SET_VALUE, |key| check(unsafe {
+            RegSetValueExW(
+                key, to_wide(VALUE_NAME).as_ptr(), 0, REG_SZ,
+                data.as_ptr() as *const u8, (data.len() * std::mem::size_of::<u16>()) as u32,
+            )
+        }))
+    }
+
+    pub fn disable() -> io::Result<()> {
+        use winapi::um::winreg::RegDeleteValueW;
+
+        with_run_key(KEY_SET_VALUE, |key| check(unsafe { RegDeleteValueW(key, to_wide(VALUE_NAME).as_ptr()) }))
+    }
+}
+
+/// Uses an XDG autostart entry, which desktop environments start on login.
+#[cfg(not(windows))]
+mod platform {
+    use std::fs;
+    use std::io;
+
+    fn entry_path() -> io::Result<std::path::PathBuf> {
+        crate::config::config_dir()
+            .map(|dir| dir.join("autostart").join("wallpaper-rs.desktop"))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Neither XDG_CONFIG_HOME nor HOME is set"))
+    }
+
+    pub fn is_enabled() -> bool {
+        entry_path().map(|path| path.exists()).unwrap_or(false)
+    }
+
+    pub fn enable(command: &str) -> io::Result<()> {
+        let path = entry_path()?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, format!(
+            "[Desktop Entry]\nType=Application\nName=wallpaper-rs\nExec={}\nNoDisplay=true\n",
+            command,
+        ))
+    }
+
+    pub fn disable() -> io::Result<()> {
+        match fs::remove_file(entry_path()?) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Checks whether wallpaper-rs starts at login.
+pub fn is_enabled() -> bool {
+    platform::is_enabled()
+}
+
+/// Makes wallpaper-rs start at login, or stop doing so.
+pub fn set_enabled(enabled: bool) -> io::Result<()> {
+    if enabled {
+        platform::enable(&login_command()?)
+    } else {
+        platform::disable()
+    }
+}
@@ -0,0 +1,450 @@
+//! Simulated desktop used to test `Engine` without a real windowing system.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::Duration;
+
+use crate::geometry::{self, DEFAULT_DPI};
+use crate::wallpaper::WallpaperError;
+
+use super::{DesktopBackend, Monitor, Rect, WindowId};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FakeWindow {
+    pub title: String,
+    pub class: String,
+    pub pid: u32,
+    pub visible: bool,
+    pub tool_window: bool,
+    /// Whether the window has its caption and borders (i.e. was not stripped by `attach`).
+    pub decorated: bool,
+    /// Window rect in screen coordinates.
+    pub rect: Rect,
+    pub parent: Option<WindowId>,
+}
+
+impl FakeWindow {
+
+    pub fn new(title: &str, class: &str, pid: u32) -> FakeWindow {
+        FakeWindow {
+            title: title.to_owned(),
+            class: class.to_owned(),
+            pid,
+            visible: true,
+            tool_window: false,
+            decorated: true,
+            rect: Rect { left: 100, top: 100, right: 740, bottom: 580 },
+            parent: None,
+        }
+    }
+
+    pub fn hidden(mut self) -> FakeWindow {
+        self.visible = false;
+        self
+    }
+
+    pub fn tool_window(mut self) -> FakeWindow {
+        self.tool_window = true;
+        self
+    }
+
+    pub fn at(mut self, rect: Rect) -> FakeWindow {
+        self.rect = rect;
+        self
+    }
+
+}
+
+/// Windows which will be created by the next spawned process.
+#[derive(Debug)]
+struct Launch {
+    windows: Vec<FakeWindow>,
+    /// How many window enumerations should pass before windows show up.
+    delay: usize,
+}
+
+#[derive(Debug)]
+struct Pending {
+    window: FakeWindow,
+    delay: usize,
+}
+
+#[derive(Debug)]
+struct State {
+    next_id: u64,
+    next_pid: u32,
+    layer: WindowId,
+    /// Cleared by `abandon_layer`.
+    layer_valid: bool,
+    layer_changes: usize,
+    layer_resizes: usize,
+    layer_rect: Rect,
+    monitors: Vec<Monitor>,
+    windows: BTreeMap<WindowId, FakeWindow>,
+    /// Every window ever created, from bottom to top.
+    stacking: Vec<WindowId>,
+    /// Executable of every known process by pid.
+    processes: BTreeMap<u32, PathBuf>,
+    /// Parent of every known process by pid.
+    parents: HashMap<u32, u32>,
+    launches: Vec<Launch>,
+    pending: Vec<Pending>,
+    spawned: Vec<String>,
+    closed: Vec<WindowId>,
+    polls: usize,
+}
+
+impl State {
+
+    fn insert(&mut self, window: FakeWindow) -> WindowId {
+        let id = WindowId(self.next_id);
+        self.next_id += 1;
+        self.windows.insert(id, window);
+        self.stacking.push(id);
+        id
+    }
+
+    /// Puts `window` on top of all others.
+    fn raise(&mut self, window: WindowId) {
+        self.stacking.retain(|&id| id != window);
+        self.stacking.push(window);
+    }
+
+    /// Advances simulated time by one window enumeration.
+    fn poll(&mut self) {
+        self.polls += 1;
+
+        let (ready, waiting): (Vec<Pending>, Vec<Pending>) = self.pending.drain(..)
+            .partition(|pending| pending.delay == 0);
+
+        self.pending = waiting.into_iter()
+            .map(|pending| Pending { delay: pending.delay - 1, ..pending })
+            .collect();
+
+        for pending in ready {
+            self.insert(pending.window);
+        }
+    }
+
+    fn window(&self, window: WindowId) -> &FakeWindow {
+        self.windows.get(&window).unwrap_or_else(|| panic!("No such window: {:?}", window))
+    }
+
+    fn window_mut(&mut self, window: WindowId) -> &mut FakeWindow {
+        self.windows.get_mut(&window).unwrap_or_else(|| panic!("No such window: {:?}", window))
+    }
+
+}
+
+/**
+ * In-memory window manager implementing `DesktopBackend`.
+ *
+ * Starts with a single 1920x1080 monitor and a wallpaper layer covering it. Spawned processes
+ * don't run anything: they only get a pid and, if a launch was scheduled with `on_spawn`,
+ * their windows appear after the given number of window enumerations.
+ */
+#[derive(Debug)]
+pub struct FakeDesktop {
+    state: RefCell<State>,
+}
+
+impl FakeDesktop {
+
+    pub fn new() -> FakeDesktop {
+        let screen = Rect { left: 0, top: 0, right: 1920, bottom: 1080 };
+        FakeDesktop::with_monitors(vec![
+            Monitor { name: "DISPLAY1".to_owned(), primary: true, rect: screen, work_area: screen, dpi: DEFAULT_DPI },
+        ])
+    }
+
+    pub fn with_monitors(monitors: Vec<Monitor>) -> FakeDesktop {
+        let layer_rect = geometry::virtual_screen(&monitors).expect("at least one monitor");
+
+        let mut state = State {
+            next_id: 1,
+            next_pid: 1000,
+            layer: WindowId(0),
+            layer_valid: true,
+            layer_changes: 0,
+            layer_resizes: 0,
+            layer_rect,
+            monitors,
+            windows: BTreeMap::new(),
+            stacking: Vec::new(),
+            processes: BTreeMap::new(),
+            parents: HashMap::new(),
+            launches: Vec::new(),
+            pending: Vec::new(),
+            spawned: Vec::new(),
+            closed: Vec::new(),
+            polls: 0,
+        };
+        state.layer = state.insert(FakeWindow::new("", "WorkerW", 1).tool_window().at(layer_rect));
+
+        FakeDesktop { state: RefCell::new(state) }
+    }
+
+    pub fn add(&self, window: FakeWindow) -> WindowId {
+        self.state.borrow_mut().insert(window)
+    }
+
+    pub fn window(&self, window: WindowId) -> FakeWindow {
+        self.state.borrow().window(window).clone()
+    }
+
+    pub fn exists(&self, window: WindowId) -> bool {
+        self.state.borrow().windows.contains_key(&window)
+    }
+
+    /// Sets the executable of process `pid`.
+    pub fn set_process(&self, pid: u32, path: &str) {
+        self.state.borrow_mut().processes.insert(pid, PathBuf::from(path));
+    }
+
+    /// Makes process `pid` a child of `parent`.
+    pub fn set_parent_process(&self, pid: u32, parent: u32) {
+        self.state.borrow_mut().parents.insert(pid, parent);
+    }
+
+    pub fn layer(&self) -> WindowId {
+        self.state.borrow().layer
+    }
+
+    /// Schedules `windows` to be created by the next spawned process after `delay` enumerations.
+    /// Windows with pid 0 get the pid of the spawned process, others are owned by a child of it.
+    pub fn on_spawn(&self, windows: Vec<FakeWindow>, delay: usize) {
+        self.state.borrow_mut().launches.push(Launch { windows, delay });
+    }
+
+    /// Destroys the wallpaper layer together with every window attached to it, like an
+    /// explorer.exe restart does.
+    pub fn destroy_layer(&self) {
+        let mut state = self.state.borrow_mut();
+        let layer = state.layer;
+        state.windows.retain(|&id, window| id != layer && window.parent != Some(layer));
+    }
+
+    /// Leaves the wallpaper layer and its windows alone but stops treating it as the layer, like
+    /// explorer.exe putting desktop icons in front of a different `WorkerW`.
+    pub fn abandon_layer(&self) {
+        self.state.borrow_mut().layer_valid = false;
+    }
+
+    /// Replaces the monitors, like plugging displays in or out. The layer is resized to cover
+    /// them, and windows in it keep their position relative to its corner.
+    pub fn set_monitors(&self, monitors: Vec<Monitor>) {
+        let mut state = self.state.borrow_mut();
+        let old = state.layer_rect;
+        let new = geometry::virtual_screen(&monitors).expect("at least one monitor");
+        let layer = state.layer;
+        for (&id, window) in state.windows.iter_mut() {
+            if id == layer {
+                window.rect = new;
+            } else if window.parent == Some(layer) {
+                window.rect = window.rect.offset(new.left - old.left, new.top - old.top);
+            }
+        }
+        state.layer_rect = new;
+        state.monitors = monitors;
+    }
+
+    /// Number of times the layer was acquired again.
+    pub fn layer_changes(&self) -> usize {
+        self.state.borrow().layer_changes
+    }
+
+    /// Number of times the engine asked to resize the layer.
+    pub fn layer_resizes(&self) -> usize {
+        self.state.borrow().layer_resizes
+    }
+
+    /// Programs started through `spawn`, in order.
+    pub fn spawned(&self) -> Vec<String> {
+        self.state.borrow().spawned.clone()
+    }
+
+    /// Windows which received a close request, in order.
+    pub fn closed(&self) -> Vec<WindowId> {
+        self.state.borrow().closed.clone()
+    }
+
+    /// Number of window enumerations performed so far.
+    pub fn polls(&self) -> usize {
+        self.state.borrow().polls
+    }
+
+}
+
+impl DesktopBackend for FakeDesktop {
+
+    const FORBIDDEN_CLASSES: &'static [&'static str] = &["Shell_TrayWnd"];
+
+    type SavedWindow = FakeWindow;
+
+    fn list_windows(&self) -> Result<Vec<WindowId>, WallpaperError> {
+        let mut state = self.state.borrow_mut();
+        state.poll();
+        Ok(state.windows.iter()
+            .filter(|(_, window)| window.parent.is_none() && window.visible && !window.tool_window)
+            .filter(|(_, window)| !window.title.is_empty())
+            .map(|(&id, _)| id)
+            .collect())
+    }
+
+    fn list_attached(&self) -> Result<Vec<WindowId>, WallpaperError> {
+        let state = self.state.borrow();
+        Ok(state.stacking.iter()
+            .filter(|id| state.windows.get(id).and_then(|window| window.parent) == Some(state.layer))
+            .cloned()
+            .collect())
+    }
+
+    fn window_exists(&self, window: WindowId) -> bool {
+        self.exists(window)
+    }
+
+    fn window_title(&self, window: WindowId) -> String {
+        self.state.borrow().window(window).title.clone()
+    }
+
+    fn window_class(&self, window: WindowId) -> String {
+        self.state.borrow().window(window).class.clone()
+    }
+
+    fn window_pid(&self, window: WindowId) -> u32 {
+        self.state.borrow().window(window).pid
+    }
+
+    fn process_path(&self, pid: u32) -> Option<PathBuf> {
+        self.state.borrow().processes.get(&pid).cloned()
+    }
+
+    fn process_parents(&self) -> HashMap<u32, u32> {
+        self.state.borrow().parents.clone()
+    }
+
+    fn window_rect(&self, window: WindowId) -> Result<Rect, WallpaperError> {
+        self.state.borrow().windows.get(&window).map(|window| window.rect).ok_or(WallpaperError::WindowNotFound)
+    }
+
+    fn is_layer_valid(&self) -> bool {
+        let state = self.state.borrow();
+        state.layer_valid && state.windows.contains_key(&state.layer)
+    }
+
+    fn reacquire_layer(&self) -> Result<(), WallpaperError> {
+        let mut state = self.state.borrow_mut();
+        let layer_rect = state.layer_rect;
+        state.layer = state.insert(FakeWindow::new("", "WorkerW", 1).tool_window().at(layer_rect));
+        state.layer_valid = true;
+        state.layer_changes += 1;
+        Ok(())
+    }
+
+    fn is_wallpaper_layer(&self, window: WindowId) -> bool {
+        self.state.borrow().layer == window
+    }
+
+    fn is_attached(&self, window: WindowId) -> bool {
+        let state = self.state.borrow();
+        state.window(window).parent == Some(state.layer)
+    }
+
+    fn attach(&self, window: WindowId) -> Result<FakeWindow, WallpaperError> {
+        let mut state = self.state.borrow_mut();
+        let layer = state.layer;
+        state.raise(window);
+        let window = state.window_mut(window);
+        let saved = window.clone();
+        window.parent = Some(layer);
+        window.decorated = false;
+        window.visible = true;
+        Ok(saved)
+    }
+
+    fn detach(&self, window: WindowId, saved: Option<FakeWindow>) -> Result<(), WallpaperError> {
+        let mut state = self.state.borrow_mut();
+        let window = state.window_mut(window);
+        match saved {
+            Some(saved) => {
+                window.parent = saved.parent;
+                window.decorated = saved.decorated;
+                window.visible = saved.visible;
+                window.rect = saved.rect;
+            },
+            None => {
+                window.parent = None;
+                window.decorated = true;
+            },
+        }
+        Ok(())
+    }
+
+    fn close_window(&self, window: WindowId) {
+        let mut state = self.state.borrow_mut();
+        state.windows.remove(&window);
+        state.closed.push(window);
+    }
+
+    fn move_window(&self, window: WindowId, rect: Rect) -> Result<(), WallpaperError> {
+        let mut state = self.state.borrow_mut();
+        let origin = state.layer_rect;
+        state.window_mut(window).rect = rect.offset(origin.left, origin.top);
+        Ok(())
+    }
+
+    fn restack(&self, windows: &[WindowId]) -> Result<(), WallpaperError> {
+        let mut state = self.state.borrow_mut();
+        for &window in windows {
+            state.raise(window);
+        }
+        Ok(())
+    }
+
+    fn map_to_layer(&self, rect: Rect) -> Rect {
+        let origin = self.state.borrow().layer_rect;
+        geometry::screen_to_layer(rect, (origin.left, origin.top))
+    }
+
+    fn resize_layer(&self) -> Result<(), WallpaperError> {
+        // `set_monitors` already resized it, the way explorer.exe does
+        self.state.borrow_mut().layer_resizes += 1;
+        Ok(())
+    }
+
+    fn monitors(&self) -> Result<Vec<Monitor>, WallpaperError> {
+        Ok(self.state.borrow().monitors.clone())
+    }
+
+    fn wait_for_changes(&self, timeout: Duration) -> Duration {
+        // time is simulated by window enumerations, nothing to wait for
+        timeout
+    }
+
+    fn redraw(&self) {}
+
+    fn spawn(&self, command: &mut Command) -> std::io::Result<u32> {
+        let mut state = self.state.borrow_mut();
+        let pid = state.next_pid;
+        state.next_pid += 1;
+        state.spawned.push(command.get_program().to_string_lossy().into_owned());
+        state.processes.insert(pid, PathBuf::from(command.get_program()));
+
+        if !state.launches.is_empty() {
+            let launch = state.launches.remove(0);
+            for mut window in launch.windows {
+                if window.pid == 0 {
+                    window.pid = pid;
+                } else {
+                    state.parents.insert(window.pid, pid);
+                }
+                state.pending.push(Pending { window, delay: launch.delay });
+            }
+        }
+
+        Ok(pid)
+    }
+
+}
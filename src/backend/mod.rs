@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::Duration;
+
+use serde::{Serialize, Deserialize};
+
+use crate::geometry::nearest_monitor;
+use crate::wallpaper::WallpaperError;
+
+pub use crate::geometry::{Monitor, Rect};
+
+#[cfg(windows)]
+pub mod windows;
+
+#[cfg(test)]
+pub mod fake;
+
+#[cfg(all(unix, not(target_os = "macos"), not(feature = "wayland")))]
+pub mod x11;
+
+#[cfg(all(unix, not(target_os = "macos"), feature = "wayland"))]
+pub mod wayland;
+
+#[cfg(windows)]
+pub use self::windows::WindowsBackend as Native;
+
+#[cfg(all(unix, not(target_os = "macos"), not(feature = "wayland")))]
+pub use self::x11::X11Backend as Native;
+
+#[cfg(all(unix, not(target_os = "macos"), feature = "wayland"))]
+pub use self::wayland::WaylandBackend as Native;
+
+/// Platform-neutral identifier of a top-level window.
+///
+/// On Windows this is the `HWND` value, on other platforms whatever the windowing system uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct WindowId(pub u64);
+
+/// Executable of process `pid`, as reported by procfs.
+#[cfg(all(unix, not(target_os = "macos")))]
+pub(crate) fn proc_executable(pid: u32) -> Option<PathBuf> {
+    std::fs::read_link(format!("/proc/{}/exe", pid)).ok()
+}
+
+/// Parent pid of every running process, as reported by procfs.
+#[cfg(all(unix, not(target_os = "macos")))]
+pub(crate) fn proc_parents() -> HashMap<u32, u32> {
+    let entries = match std::fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return HashMap::new(),
+    };
+
+    entries.filter_map(|entry| {
+        let pid = entry.ok()?.file_name().to_str()?.parse().ok()?;
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+        // the command name may contain spaces and parentheses, ppid is the 2nd field after it
+        let ppid = stat[stat.rfind(')')? + 1..].split_whitespace().nth(1)?.parse().ok()?;
+        Some((pid, ppid))
+    }).collect()
+}
+
+/**
+ * Everything `Engine` needs from the windowing system.
+ *
+ * The "wallpaper layer" is whatever sits between the desktop background and desktop icons:
+ * the `WorkerW` window on Windows. Unless stated otherwise, rects are in screen coordinates.
+ */
+pub trait DesktopBackend {
+
+    /// Window classes which must never be turned into wallpapers (taskbars, the desktop itself, ...).
+    const FORBIDDEN_CLASSES: &'static [&'static str];
+
+    /// Whether wallpapers stay on the desktop after this process exits. If they don't, a later
+    /// process can't find them either, so only a long-running instance can manage them.
+    const WALLPAPERS_OUTLIVE_PROCESS: bool = true;
+
+    /// Whatever `attach` changes about a window (styles, parent, geometry, ...), recorded so
+    /// `detach` can undo it exactly.
+    type SavedWindow: Debug;
+
+    /// Lists top-level windows the way the task switcher does.
+    fn list_windows(&self) -> Result<Vec<WindowId>, WallpaperError>;
+
+    /// Lists windows which are direct children of the wallpaper layer, from bottom to top.
+    fn list_attached(&self) -> Result<Vec<WindowId>, WallpaperError>;
+
+    /// Checks whether `window` still refers to an existing window.
+    fn window_exists(&self, window: WindowId) -> bool;
+
+    fn window_title(&self, window: WindowId) -> String;
+
+    fn window_class(&self, window: WindowId) -> String;
+
+    fn window_pid(&self, window: WindowId) -> u32;
+
+    /// Full path to the executable of process `pid`, if it can be queried.
+    fn process_path(&self, pid: u32) -> Option<PathBuf>;
+
+    /// Parent pid of every running process, keyed by pid.
+    fn process_parents(&self) -> HashMap<u32, u32>;
+
+    fn window_rect(&self, window: WindowId) -> Result<Rect, WallpaperError>;
+
+    /// Checks whether the wallpaper layer still exists and still sits behind desktop icons. It
+    /// goes away with whoever owns it, e.g. `WorkerW` is destroyed together with every wallpaper
+    /// in it when explorer.exe restarts.
+    fn is_layer_valid(&self) -> bool {
+        true
+    }
+
+    /// Finds or creates the wallpaper layer again once it was lost.
+    fn reacquire_layer(&self) -> Result<(), WallpaperError> {
+        Ok(())
+    }
+
+    /// Checks whether `window` is the wallpaper layer itself.
+    fn is_wallpaper_layer(&self, window: WindowId) -> bool;
+
+    /// Checks whether `window` is already attached to the wallpaper layer.
+    fn is_attached(&self, window: WindowId) -> bool;
+
+    /// Strips window decorations and reparents `window` into the wallpaper layer. Returns the
+    /// window state from before the change.
+    fn attach(&self, window: WindowId) -> Result<Self::SavedWindow, WallpaperError>;
+
+    /// Moves `window` out of the wallpaper layer and restores the state saved by `attach`.
+    /// Without `saved` (e.g. the window was attached by an earlier run) it only gives the window
+    /// back its decorations.
+    fn detach(&self, window: WindowId, saved: Option<Self::SavedWindow>) -> Result<(), WallpaperError>;
+
+    /// Politely asks `window` to close.
+    fn close_window(&self, window: WindowId);
+
+    /// Moves and resizes an attached window. `rect` is in wallpaper layer coordinates.
+    fn move_window(&self, window: WindowId, rect: Rect) -> Result<(), WallpaperError>;
+
+    /// Puts attached `windows` above the other windows in the wallpaper layer, stacked in order
+    /// from bottom to top.
+    fn restack(&self, _windows: &[WindowId]) -> Result<(), WallpaperError> {
+        Err(WallpaperError::Unsupported)
+    }
+
+    /// Converts `rect` from screen coordinates to wallpaper layer coordinates.
+    fn map_to_layer(&self, rect: Rect) -> Rect;
+
+    /// Lists all monitors. Their position in the list is the monitor index shown to users, so
+    /// the order should be stable as long as the display configuration doesn't change.
+    fn monitors(&self) -> Result<Vec<Monitor>, WallpaperError>;
+
+    /// Checks whether the display configuration may have changed since the last call. Backends
+    /// which aren't notified of changes always say so, and `Engine` compares monitors instead.
+    fn displays_changed(&self) -> bool {
+        true
+    }
+
+    /// Makes the wallpaper layer cover all monitors again after they changed, unless the system
+    /// does it on its own like explorer.exe does for `WorkerW`.
+    fn resize_layer(&self) -> Result<(), WallpaperError> {
+        Ok(())
+    }
+
+    /// Returns the monitor containing point (`x`, `y`), or the nearest one.
+    fn monitor_at(&self, x: i32, y: i32) -> Result<Monitor, WallpaperError> {
+        nearest_monitor(&self.monitors()?, x, y).ok_or(WallpaperError::MonitorNotFound)
+    }
+
+    /// Blocks until a window is created, shown or renamed, or until `timeout` passes. Returns
+    /// the time spent waiting. Backends which can't watch windows simply sleep.
+    fn wait_for_changes(&self, timeout: Duration) -> Duration {
+        std::thread::sleep(timeout);
+        timeout
+    }
+
+    /// Forces the whole desktop to repaint, cleaning up leftovers of removed wallpapers.
+    fn redraw(&self);
+
+    /// Starts `command` and returns its process id.
+    fn spawn(&self, command: &mut Command) -> std::io::Result<u32> {
+        command.spawn().map(|child| child.id())
+    }
+
+}
@@ -0,0 +1,752 @@
+//! Wayland backend.
+//!
+//! Wayland does not let clients reparent or restack windows of other clients, so existing
+//! windows can't be turned into wallpapers at all. Spawned processes can, as long as they put
+//! their surfaces on the `zwlr_layer_shell_v1` background layer themselves: Qt clients are asked
+//! to do so through the layer-shell-qt integration, others (mpvpaper, swaybg, ...) do it on
+//! their own.
+//!
+//! Spawned processes talk to the compositor through a proxy which forwards everything untouched
+//! and only watches requests creating surfaces. Every toplevel and layer surface it sees is what
+//! this backend calls a "window", and only background layer surfaces can be attached, so clients
+//! which open regular toplevels are never reported as wallpapers.
+//!
+//! All rects are in the compositor's logical coordinates, which is also what layer surfaces are
+//! sized in. Requires a compositor implementing `zwlr_layer_shell_v1` (sway, Hyprland, KWin,
+//! river, ...).
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::mem::{size_of, size_of_val};
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use wayland_client::globals::{registry_queue_init, GlobalListContents};
+use wayland_client::protocol::{wl_output, wl_registry};
+use wayland_client::{Connection, Dispatch, QueueHandle, WEnum};
+use wayland_protocols::xdg::xdg_output::zv1::client::{zxdg_output_manager_v1, zxdg_output_v1};
+
+use crate::geometry::DEFAULT_DPI;
+use crate::wallpaper::WallpaperError;
+
+use super::{proc_executable, proc_parents, DesktopBackend, Monitor, Rect, WindowId};
+
+const LAYER_SHELL: &str = "zwlr_layer_shell_v1";
+
+const XDG_OUTPUT_MANAGER: &str = "zxdg_output_manager_v1";
+
+/// `zwlr_layer_shell_v1::layer::background`.
+const BACKGROUND_LAYER: u32 = 0;
+
+/// Environment which asks layer-shell-qt to put every window of the process on the background layer.
+const LAYER_SHELL_QT_ENV: &[(&str, &str)] = &[
+    ("QT_WAYLAND_SHELL_INTEGRATION", "layer-shell"),
+    ("LAYERSHELLQT_LAYER", "LayerBackground"),
+    ("LAYERSHELLQT_ANCHORS", "AnchorTop|AnchorBottom|AnchorLeft|AnchorRight"),
+    ("LAYERSHELLQT_EXCLUSIVE_ZONE", "-1"),
+    ("LAYERSHELLQT_KEYBOARD_INTERACTIVITY", "KeyboardInteractivityNone"),
+];
+
+#[derive(Debug, Default)]
+struct Output {
+    /// Name of the `wl_output` global, the same for every client of the compositor.
+    global: u32,
+    name: String,
+    x: i32,
+    y: i32,
+    /// Current mode, in physical pixels.
+    mode: (i32, i32),
+    /// Whether the output is rotated by 90 or 270 degrees, which swaps the mode's sides.
+    rotated: bool,
+    /// Integer scale factor, 0 until the compositor sends one.
+    scale: i32,
+    /// Logical position and size, if the compositor supports `zxdg_output_manager_v1`.
+    logical_position: Option<(i32, i32)>,
+    logical_size: Option<(i32, i32)>,
+}
+
+impl Output {
+
+    fn to_monitor(&self, primary: bool) -> Monitor {
+        let (width, height) = if self.rotated { (self.mode.1, self.mode.0) } else { self.mode };
+        let scale = self.scale.max(1);
+        let (x, y) = self.logical_position.unwrap_or((self.x, self.y));
+        // without xdg-output the integer scale is all there is, fractional scales are rounded up
+        let (logical_width, logical_height) = self.logical_size.unwrap_or((width / scale, height / scale));
+
+        let rect = Rect { left: x, top: y, right: x + logical_width, bottom: y + logical_height };
+        // layer-shell has no notion of work areas, panels reserve space on their own
+        let dpi = if logical_width > 0 {
+            (DEFAULT_DPI as f64 * width as f64 / logical_width as f64).round() as u32
+        } else {
+            DEFAULT_DPI
+        };
+        Monitor { name: self.name.clone(), primary, rect, work_area: rect, dpi }
+    }
+
+}
+
+#[derive(Debug, Default)]
+struct Outputs(Vec<Output>);
+
+impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for Outputs {
+    fn event(
+        _: &mut Self, _: &wl_registry::WlRegistry, _: wl_registry::Event,
+        _: &GlobalListContents, _: &Connection, _: &QueueHandle<Self>,
+    ) {}
+}
+
+impl Dispatch<wl_output::WlOutput, usize> for Outputs {
+    fn event(
+        state: &mut Self, _: &wl_output::WlOutput, event: wl_output::Event,
+        index: &usize, _: &Connection, _: &QueueHandle<Self>,
+    ) {
+        let output = &mut state.0[*index];
+        match event {
+            wl_output::Event::Name { name } => output.name = name,
+            wl_output::Event::Geometry { x, y, transform, .. } => {
+                output.x = x;
+                output.y = y;
+                output.rotated = matches!(transform, WEnum::Value(
+                    wl_output::Transform::_90 | wl_output::Transform::_270
+                    | wl_output::Transform::Flipped90 | wl_output::Transform::Flipped270
+                ));
+            },
+            wl_output::Event::Mode { flags: WEnum::Value(flags), width, height, .. }
+                if flags.contains(wl_output::Mode::Current) => output.mode = (width, height),
+            wl_output::Event::Scale { factor } => output.scale = factor,
+            _ => {},
+        }
+    }
+}
+
+impl Dispatch<zxdg_output_manager_v1::ZxdgOutputManagerV1, ()> for Outputs {
+    fn event(
+        _: &mut Self, _: &zxdg_output_manager_v1::ZxdgOutputManagerV1, _: zxdg_output_manager_v1::Event,
+        _: &(), _: &Connection, _: &QueueHandle<Self>,
+    ) {}
+}
+
+impl Dispatch<zxdg_output_v1::ZxdgOutputV1, usize> for Outputs {
+    fn event(
+        state: &mut Self, _: &zxdg_output_v1::ZxdgOutputV1, event: zxdg_output_v1::Event,
+        index: &usize, _: &Connection, _: &QueueHandle<Self>,
+    ) {
+        let output = &mut state.0[*index];
+        match event {
+            zxdg_output_v1::Event::LogicalPosition { x, y } => output.logical_position = Some((x, y)),
+            zxdg_output_v1::Event::LogicalSize { width, height } => output.logical_size = Some((width, height)),
+            _ => {},
+        }
+    }
+}
+
+/// Checks whether the compositor supports layer-shell and reads geometry of every output, keyed
+/// by the name of its `wl_output` global.
+fn get_outputs(conn: &Connection) -> Option<(bool, Vec<(u32, Monitor)>)> {
+    let (globals, mut queue) = registry_queue_init::<Outputs>(conn).ok()?;
+    let handle = queue.handle();
+
+    let mut outputs = Outputs::default();
+    let mut wl_outputs = Vec::new();
+    let mut has_layer_shell = false;
+    let mut output_manager = None;
+    for global in globals.contents().clone_list() {
+        match global.interface.as_str() {
+            LAYER_SHELL => has_layer_shell = true,
+            XDG_OUTPUT_MANAGER => {
+                output_manager = Some(globals.registry().bind::<zxdg_output_manager_v1::ZxdgOutputManagerV1, _, _>(
+                    global.name, global.version.min(3), &handle, (),
+                ));
+            },
+            "wl_output" => {
+                let index = outputs.0.len();
+                outputs.0.push(Output { global: global.name, ..Output::default() });
+                wl_outputs.push(globals.registry().bind::<wl_output::WlOutput, _, _>(
+                    global.name, global.version.min(4), &handle, index,
+                ));
+            },
+            _ => {},
+        }
+    }
+    if let Some(manager) = &output_manager {
+        for (index, output) in wl_outputs.iter().enumerate() {
+            manager.get_xdg_output(output, &handle, index);
+        }
+    }
+    queue.roundtrip(&mut outputs).ok()?;
+
+    // Wayland has no primary output, the first one announced stands in for it
+    let monitors = outputs.0.iter().enumerate()
+        .map(|(index, output)| (output.global, output.to_monitor(index == 0)))
+        .collect();
+
+    Some((has_layer_shell, monitors))
+}
+
+/// A surface created by a spawned client, as seen by the proxy.
+#[derive(Debug, Clone)]
+struct Surface {
+    window: WindowId,
+    pid: u32,
+    /// Layer of a layer surface, `None` for toplevels.
+    layer: Option<u32>,
+    /// `xdg_toplevel` title, empty for layer surfaces.
+    title: String,
+    /// `xdg_toplevel` app id, or the namespace of a layer surface.
+    class: String,
+    /// Global name of the output a layer surface was put on, `None` if the compositor picks one.
+    output: Option<u32>,
+    attached: bool,
+}
+
+/// Surfaces of all proxied clients, in creation order.
+#[derive(Debug, Default)]
+struct Surfaces {
+    list: Mutex<Vec<Surface>>,
+    changed: Condvar,
+}
+
+impl Surfaces {
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Surface>> {
+        self.list.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn update(&self, window: WindowId, f: impl FnOnce(&mut Surface)) {
+        if let Some(surface) = self.lock().iter_mut().find(|surface| surface.window == window) {
+            f(surface);
+        }
+        self.changed.notify_all();
+    }
+
+}
+
+/// Proxy objects the proxy keeps track of. Everything else is forwarded without a look.
+#[derive(Debug, Clone, Copy)]
+enum Object {
+    Display,
+    Registry,
+    /// `wl_output` bound to the global of this name.
+    Output(u32),
+    LayerShell,
+    WmBase,
+    XdgSurface,
+    Toplevel(WindowId),
+    LayerSurface(WindowId),
+}
+
+/// Reads arguments of a request in the wire format: native-endian 32-bit words, strings
+/// prefixed by their length including the terminating NUL and padded to a whole word.
+struct Args<'a>(&'a [u8]);
+
+impl<'a> Args<'a> {
+
+    fn uint(&mut self) -> Option<u32> {
+        let word = self.0.get(..4)?;
+        self.0 = &self.0[4..];
+        Some(u32::from_ne_bytes([word[0], word[1], word[2], word[3]]))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let length = self.uint()? as usize;
+        let padded = (length + 3) & !3;
+        let bytes = self.0.get(..padded)?;
+        self.0 = &self.0[padded..];
+        Some(String::from_utf8_lossy(&bytes[..length.saturating_sub(1)]).into_owned())
+    }
+
+}
+
+/// Source of window ids. They are small on purpose: the UI passes them around as JavaScript
+/// numbers, which can't hold every `u64`.
+static NEXT_SURFACE: AtomicU32 = AtomicU32::new(1);
+
+/// Tracks requests of one client connection. Its surfaces go away together with it.
+struct ClientState {
+    pid: u32,
+    surfaces: Arc<Surfaces>,
+    objects: HashMap<u32, Object>,
+    owned: Vec<WindowId>,
+    /// Bytes of a request which didn't arrive completely yet.
+    pending: Vec<u8>,
+}
+
+impl ClientState {
+
+    fn new(pid: u32, surfaces: Arc<Surfaces>) -> ClientState {
+        let mut objects = HashMap::new();
+        objects.insert(1, Object::Display);
+        ClientState { pid, surfaces, objects, owned: Vec::new(), pending: Vec::new() }
+    }
+
+    fn feed(&mut self, data: &[u8]) {
+        self.pending.extend_from_slice(data);
+        let mut offset = 0;
+        while let Some(header) = self.pending.get(offset..offset + 8) {
+            let mut args = Args(header);
+            let (object, word) = match (args.uint(), args.uint()) {
+                (Some(object), Some(word)) => (object, word),
+                _ => break,
+            };
+            let size = (word >> 16) as usize;
+            if size < 8 {
+                // not the wire format, the compositor will disconnect the client anyway
+                self.pending.clear();
+                return;
+            }
+            let body = match self.pending.get(offset + 8..offset + size) {
+                Some(body) => body.to_vec(),
+                None => break,
+            };
+            self.handle(object, (word & 0xffff) as u16, Args(&body));
+            offset += size;
+        }
+        self.pending.drain(..offset);
+    }
+
+    fn add_surface(&mut self, layer: Option<u32>, class: String, output: Option<u32>) -> WindowId {
+        let window = WindowId(u64::from(NEXT_SURFACE.fetch_add(1, Ordering::Relaxed)));
+        let surface = Surface { window, pid: self.pid, layer, title: String::new(), class, output, attached: false };
+        self.surfaces.lock().push(surface);
+        self.surfaces.changed.notify_all();
+        self.owned.push(window);
+        window
+    }
+
+    fn remove_surface(&mut self, window: WindowId) {
+        self.surfaces.lock().retain(|surface| surface.window != window);
+        self.surfaces.changed.notify_all();
+        self.owned.retain(|&owned| owned != window);
+    }
+
+    fn handle(&mut self, object: u32, opcode: u16, mut args: Args) -> Option<()> {
+        match (*self.objects.get(&object)?, opcode) {
+            // wl_display.get_registry
+            (Object::Display, 1) => {
+                self.objects.insert(args.uint()?, Object::Registry);
+            },
+            // wl_registry.bind
+            (Object::Registry, 0) => {
+                let name = args.uint()?;
+                let interface = args.string()?;
+                let _version = args.uint()?;
+                let id = args.uint()?;
+                let bound = match interface.as_str() {
+                    "wl_output" => Object::Output(name),
+                    "xdg_wm_base" => Object::WmBase,
+                    LAYER_SHELL => Object::LayerShell,
+                    _ => return None,
+                };
+                self.objects.insert(id, bound);
+            },
+            // wl_output.release, zwlr_layer_shell_v1.destroy, xdg_wm_base.destroy, xdg_surface.destroy
+            (Object::Output(_), 0) | (Object::LayerShell, 1) | (Object::WmBase, 0) | (Object::XdgSurface, 0) => {
+                self.objects.remove(&object);
+            },
+            // zwlr_layer_shell_v1.get_layer_surface
+            (Object::LayerShell, 0) => {
+                let id = args.uint()?;
+                let _surface = args.uint()?;
+                let output = match args.uint()? {
+                    0 => None,
+                    output => match self.objects.get(&output) {
+                        Some(Object::Output(name)) => Some(*name),
+                        _ => None,
+                    },
+                };
+                let layer = args.uint()?;
+                let namespace = args.string()?;
+                let window = self.add_surface(Some(layer), namespace, output);
+                self.objects.insert(id, Object::LayerSurface(window));
+            },
+            // zwlr_layer_surface_v1.destroy
+            (Object::LayerSurface(window), 7) => {
+                self.remove_surface(window);
+                self.objects.remove(&object);
+            },
+            // zwlr_layer_surface_v1.set_layer
+            (Object::LayerSurface(window), 8) => {
+                let layer = args.uint()?;
+                self.surfaces.update(window, |surface| surface.layer = Some(layer));
+            },
+            // xdg_wm_base.get_xdg_surface
+            (Object::WmBase, 2) => {
+                self.objects.insert(args.uint()?, Object::XdgSurface);
+            },
+            // xdg_surface.get_toplevel
+            (Object::XdgSurface, 1) => {
+                let id = args.uint()?;
+                let window = self.add_surface(None, String::new(), None);
+                self.objects.insert(id, Object::Toplevel(window));
+            },
+            // xdg_toplevel.destroy
+            (Object::Toplevel(window), 0) => {
+                self.remove_surface(window);
+                self.objects.remove(&object);
+            },
+            // xdg_toplevel.set_title
+            (Object::Toplevel(window), 2) => {
+                let title = args.string()?;
+                self.surfaces.update(window, |surface| surface.title = title);
+            },
+            // xdg_toplevel.set_app_id
+            (Object::Toplevel(window), 3) => {
+                let class = args.string()?;
+                self.surfaces.update(window, |surface| surface.class = class);
+            },
+            _ => {},
+        }
+        Some(())
+    }
+
+}
+
+impl Drop for ClientState {
+    fn drop(&mut self) {
+        for window in self.owned.clone() {
+            self.remove_surface(window);
+        }
+    }
+}
+
+/// Most file descriptors libwayland sends along with a single chunk of data.
+const MAX_FDS: usize = 28;
+
+/// Receives data from `socket` together with file descriptors passed along with it.
+fn recv_with_fds(socket: &UnixStream, buffer: &mut [u8], fds: &mut Vec<RawFd>) -> io::Result<usize> {
+    let mut control = [0usize; MAX_FDS];
+    loop {
+        let mut iov = libc::iovec { iov_base: buffer.as_mut_ptr() as *mut libc::c_void, iov_len: buffer.len() };
+        // SAFETY: msghdr is plain data, all pointers set below outlive the call
+        let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = size_of_val(&control) as _;
+
+        // SAFETY: see above
+        let read = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut message, libc::MSG_CMSG_CLOEXEC) };
+        if read < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(error);
+        }
+
+        // SAFETY: the control messages were written by the kernel into `control`
+        unsafe {
+            let mut header = libc::CMSG_FIRSTHDR(&message);
+            while !header.is_null() {
+                if (*header).cmsg_level == libc::SOL_SOCKET && (*header).cmsg_type == libc::SCM_RIGHTS {
+                    let data = libc::CMSG_DATA(header) as *const RawFd;
+                    let count = ((*header).cmsg_len as usize - libc::CMSG_LEN(0) as usize) / size_of::<RawFd>();
+                    fds.extend((0..count).map(|index| data.add(index).read_unaligned()));
+                }
+                header = libc::CMSG_NXTHDR(&message, header);
+            }
+        }
+        return Ok(read as usize);
+    }
+}
+
+/// Sends all of `data` to `socket`, passing `fds` along with the first byte.
+fn send_with_fds(socket: &UnixStream, mut data: &[u8], mut fds: &[RawFd]) -> io::Result<()> {
+    let mut control = [0usize; MAX_FDS];
+    while !data.is_empty() {
+        let mut iov = libc::iovec { iov_base: data.as_ptr() as *mut libc::c_void, iov_len: data.len() };
+        // SAFETY: msghdr is plain data, all pointers set below outlive the call
+        let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        if !fds.is_empty() {
+            let length = size_of_val(fds) as u32;
+            message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            // SAFETY: `control` has room for MAX_FDS descriptors
+            unsafe {
+                message.msg_controllen = libc::CMSG_SPACE(length) as _;
+                let header = libc::CMSG_FIRSTHDR(&message);
+                (*header).cmsg_level = libc::SOL_SOCKET;
+                (*header).cmsg_type = libc::SCM_RIGHTS;
+                (*header).cmsg_len = libc::CMSG_LEN(length) as _;
+                std::ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(header) as *mut RawFd, fds.len());
+            }
+        }
+
+        // SAFETY: see above
+        let sent = unsafe { libc::sendmsg(socket.as_raw_fd(), &message, libc::MSG_NOSIGNAL) };
+        if sent < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(error);
+        }
+        data = &data[sent as usize..];
+        fds = &[];
+    }
+    Ok(())
+}
+
+/// Forwards everything from `from` to `to`, showing the data to `inspect` first. Shuts both
+/// connections down once either of them is closed.
+fn pump(from: UnixStream, to: UnixStream, mut inspect: impl FnMut(&[u8])) {
+    let mut buffer = [0u8; 4096];
+    let mut fds = Vec::with_capacity(MAX_FDS);
+    loop {
+        fds.clear();
+        let read = match recv_with_fds(&from, &mut buffer, &mut fds) {
+            Ok(0) | Err(_) => break,
+            Ok(read) => read,
+        };
+        inspect(&buffer[..read]);
+        let sent = send_with_fds(&to, &buffer[..read], &fds);
+        for &fd in &fds {
+            // SAFETY: received descriptors are owned by us and were duplicated into `to` by the kernel
+            unsafe { libc::close(fd) };
+        }
+        if sent.is_err() {
+            break;
+        }
+    }
+    let _ = from.shutdown(Shutdown::Both);
+    let _ = to.shutdown(Shutdown::Both);
+}
+
+/// Pid of the process on the other end of `socket`.
+fn peer_pid(socket: &UnixStream) -> Option<u32> {
+    // SAFETY: ucred is plain data and `length` matches its size
+    unsafe {
+        let mut credentials: libc::ucred = std::mem::zeroed();
+        let mut length = size_of::<libc::ucred>() as libc::socklen_t;
+        let result = libc::getsockopt(
+            socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED,
+            &mut credentials as *mut libc::ucred as *mut libc::c_void, &mut length,
+        );
+        if result == 0 { Some(credentials.pid as u32) } else { None }
+    }
+}
+
+/// Connects `client` to the compositor and forwards traffic both ways in background threads.
+fn proxy_client(client: UnixStream, compositor: &Path, surfaces: &Arc<Surfaces>) -> io::Result<()> {
+    let server = UnixStream::connect(compositor)?;
+    let pid = peer_pid(&client).unwrap_or(0);
+
+    let (from_server, to_client) = (server.try_clone()?, client.try_clone()?);
+    std::thread::spawn(move || pump(from_server, to_client, |_| ()));
+
+    let mut state = ClientState::new(pid, Arc::clone(surfaces));
+    std::thread::spawn(move || pump(client, server, |data| state.feed(data)));
+    Ok(())
+}
+
+/// Path of the compositor socket, resolved the way libwayland does it.
+fn compositor_socket() -> Option<PathBuf> {
+    let display = std::env::var_os("WAYLAND_DISPLAY").unwrap_or_else(|| OsString::from("wayland-0"));
+    let display = PathBuf::from(display);
+    if display.is_absolute() {
+        Some(display)
+    } else {
+        std::env::var_os("XDG_RUNTIME_DIR").map(|dir| PathBuf::from(dir).join(display))
+    }
+}
+
+/// Wayland backend hosting background layer surfaces of spawned processes.
+#[derive(Debug)]
+pub struct WaylandBackend {
+    /// Proxy socket spawned processes connect to instead of the compositor.
+    socket: PathBuf,
+    surfaces: Arc<Surfaces>,
+    children: RefCell<Vec<Child>>,
+}
+
+impl WaylandBackend {
+
+    pub fn new() -> Result<WaylandBackend, WallpaperError> {
+        let conn = Connection::connect_to_env().map_err(|_| WallpaperError::DisplayNotFound)?;
+        let (has_layer_shell, _) = get_outputs(&conn).ok_or(WallpaperError::DisplayNotFound)?;
+        if !has_layer_shell {
+            return Err(WallpaperError::LayerShellUnsupported);
+        }
+
+        let compositor = compositor_socket().ok_or(WallpaperError::DisplayNotFound)?;
+        let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR").ok_or(WallpaperError::DisplayNotFound)?;
+        let socket = PathBuf::from(runtime_dir).join(format!("wallpaper-rs-{}", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).map_err(|_| WallpaperError::DisplayNotFound)?;
+
+        let surfaces = Arc::new(Surfaces::default());
+        let accepted = Arc::clone(&surfaces);
+        std::thread::spawn(move || {
+            for client in listener.incoming().flatten() {
+                let _ = proxy_client(client, &compositor, &accepted);
+            }
+        });
+
+        Ok(WaylandBackend { socket, surfaces, children: RefCell::new(Vec::new()) })
+    }
+
+    fn with_surface<T>(&self, window: WindowId, f: impl FnOnce(&mut Surface) -> T) -> Option<T> {
+        self.surfaces.lock().iter_mut().find(|surface| surface.window == window).map(f)
+    }
+
+    fn outputs(&self) -> Result<Vec<(u32, Monitor)>, WallpaperError> {
+        let conn = Connection::connect_to_env().map_err(|_| WallpaperError::DisplayNotFound)?;
+        get_outputs(&conn).map(|(_, outputs)| outputs).ok_or(WallpaperError::DisplayNotFound)
+    }
+
+}
+
+impl Drop for WaylandBackend {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.socket);
+    }
+}
+
+impl DesktopBackend for WaylandBackend {
+
+    const FORBIDDEN_CLASSES: &'static [&'static str] = &[];
+
+    // spawned clients lose their proxied connection when this process exits
+    const WALLPAPERS_OUTLIVE_PROCESS: bool = false;
+
+    type SavedWindow = ();
+
+    fn list_windows(&self) -> Result<Vec<WindowId>, WallpaperError> {
+        // reap exited processes, nothing else waits for them
+        self.children.borrow_mut().retain_mut(|child| matches!(child.try_wait(), Ok(None)));
+
+        // background layer surfaces first, so they win over splash screens and dialogs of the same process
+        let surfaces = self.surfaces.lock();
+        let unattached = surfaces.iter().filter(|surface| !surface.attached);
+        let (mut windows, others): (Vec<_>, Vec<_>) = unattached
+            .partition(|surface| surface.layer == Some(BACKGROUND_LAYER));
+        windows.extend(others);
+        Ok(windows.into_iter().map(|surface| surface.window).collect())
+    }
+
+    fn list_attached(&self) -> Result<Vec<WindowId>, WallpaperError> {
+        Ok(self.surfaces.lock().iter().filter(|surface| surface.attached).map(|surface| surface.window).collect())
+    }
+
+    fn window_exists(&self, window: WindowId) -> bool {
+        self.with_surface(window, |_| ()).is_some()
+    }
+
+    fn window_title(&self, window: WindowId) -> String {
+        self.with_surface(window, |surface| surface.title.clone()).unwrap_or_default()
+    }
+
+    fn window_class(&self, window: WindowId) -> String {
+        self.with_surface(window, |surface| surface.class.clone()).unwrap_or_default()
+    }
+
+    fn window_pid(&self, window: WindowId) -> u32 {
+        self.with_surface(window, |surface| surface.pid).unwrap_or(0)
+    }
+
+    fn process_path(&self, pid: u32) -> Option<PathBuf> {
+        proc_executable(pid)
+    }
+
+    fn process_parents(&self) -> HashMap<u32, u32> {
+        proc_parents()
+    }
+
+    fn window_rect(&self, window: WindowId) -> Result<Rect, WallpaperError> {
+        let (layer, output) = self.with_surface(window, |surface| (surface.layer, surface.output))
+            .ok_or(WallpaperError::WindowNotFound)?;
+        if layer.is_none() {
+            // the compositor doesn't tell other clients where toplevels are
+            return Err(WallpaperError::Unsupported);
+        }
+
+        // surfaces are anchored to all edges, so they cover the output they are on
+        let outputs = self.outputs()?;
+        let found = match output {
+            Some(output) => outputs.iter().find(|(global, _)| *global == output),
+            None => outputs.iter().find(|(_, monitor)| monitor.primary),
+        };
+        found.map(|(_, monitor)| monitor.rect).ok_or(WallpaperError::MonitorNotFound)
+    }
+
+    fn is_wallpaper_layer(&self, _window: WindowId) -> bool {
+        false
+    }
+
+    fn is_attached(&self, window: WindowId) -> bool {
+        self.with_surface(window, |surface| surface.attached).unwrap_or(false)
+    }
+
+    fn attach(&self, window: WindowId) -> Result<(), WallpaperError> {
+        // only surfaces their client put on the background layer can be wallpapers, toplevels
+        // and windows of other processes can't be touched at all
+        self.with_surface(window, |surface| {
+            if surface.layer != Some(BACKGROUND_LAYER) {
+                return Err(WallpaperError::Unsupported);
+            }
+            surface.attached = true;
+            Ok(())
+        }).unwrap_or(Err(WallpaperError::WindowNotFound))
+    }
+
+    fn detach(&self, _window: WindowId, _saved: Option<()>) -> Result<(), WallpaperError> {
+        // layer surfaces can't be turned back into regular windows
+        Err(WallpaperError::Unsupported)
+    }
+
+    fn close_window(&self, window: WindowId) {
+        // there is no way to ask a surface of another client to close, its process is asked instead
+        if let Some(pid) = self.with_surface(window, |surface| surface.pid) {
+            if pid != 0 {
+                // SAFETY: kill has no memory safety requirements
+                unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+            }
+        }
+    }
+
+    fn move_window(&self, window: WindowId, rect: Rect) -> Result<(), WallpaperError> {
+        // clients size layer surfaces themselves, so only "cover the output" placement is possible
+        if self.window_rect(window)? == rect { Ok(()) } else { Err(WallpaperError::Unsupported) }
+    }
+
+    fn map_to_layer(&self, rect: Rect) -> Rect {
+        rect
+    }
+
+    fn monitors(&self) -> Result<Vec<Monitor>, WallpaperError> {
+        Ok(self.outputs()?.into_iter().map(|(_, monitor)| monitor).collect())
+    }
+
+    fn wait_for_changes(&self, timeout: Duration) -> Duration {
+        let start = Instant::now();
+        let surfaces = self.surfaces.lock();
+        let _ = self.surfaces.changed.wait_timeout(surfaces, timeout);
+        start.elapsed()
+    }
+
+    fn redraw(&self) {}
+
+    fn spawn(&self, command: &mut Command) -> std::io::Result<u32> {
+        for (key, value) in LAYER_SHELL_QT_ENV {
+            command.env(key, value);
+        }
+        command.env("WAYLAND_DISPLAY", &self.socket).env_remove("WAYLAND_SOCKET");
+
+        let child = command.spawn()?;
+        let pid = child.id();
+        self.children.borrow_mut().push(child);
+
+        Ok(pid)
+    }
+
+}
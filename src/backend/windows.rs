@@ -0,0 +1,940 @@
+use std::cell::Cell;
+use std::ffi::{OsStr, OsString};
+use std::collections::HashMap;
+use std::iter::once;
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::ptr::null_mut;
+
+use crate::geometry::{self, DEFAULT_DPI};
+use crate::wallpaper::WallpaperError;
+
+use super::{DesktopBackend, Monitor, Rect, WindowId};
+
+use winapi::shared::windef::{DPI_AWARENESS_CONTEXT, HWND, HDC, HMONITOR, HWINEVENTHOOK, LPRECT, RECT, POINT};
+use winapi::shared::minwindef::{BOOL, DWORD, LPARAM, LRESULT, UINT, WPARAM};
+use winapi::shared::ntdef::LONG;
+use winapi::um::errhandlingapi::{GetLastError, SetLastError};
+use winapi::um::winuser::{
+    GetClassNameW, IsChild, ShowWindow, GetWindowRect,
+    MonitorFromPoint, GetMonitorInfoW, SetWindowPos,
+    SW_SHOW, 
+    MONITOR_DEFAULTTONEAREST,
+    MONITORINFO, WINDOWPLACEMENT
+};
+
+fn find_window_by_class(class: &str) -> HWND {
+    use winapi::um::winuser::FindWindowW;
+    unsafe { FindWindowW(to_wide(class).as_ptr(), null_mut()) }
+}
+
+pub(crate) fn to_wide(s: &str) -> Vec<u16> {
+    OsStr::new(s).encode_wide().chain(once(0)).collect()
+}
+
+/// Builds an error out of `GetLastError` for the failed WinAPI function `call`.
+fn last_error(call: &'static str) -> WallpaperError {
+    WallpaperError::Os { call, os_code: unsafe { GetLastError() } }
+}
+
+fn get_window_name(hwnd: HWND) -> String {
+    use winapi::um::winuser::{GetWindowTextLengthW, GetWindowTextW};
+
+    if hwnd.is_null() {
+        return String::new();
+    }
+
+    let text = unsafe {
+        let text_length = GetWindowTextLengthW(hwnd);
+        let mut text: Vec<u16> = vec![0; text_length as usize + 1];
+        GetWindowTextW(hwnd, text.as_mut_ptr(), text_length + 1);
+        OsString::from_wide(&text[..text.iter().position(|&c| c == 0).unwrap()])
+    };
+
+    text.to_string_lossy().into_owned()
+}
+
+fn get_window_class(hwnd: HWND) -> String {
+    let wnd_class = unsafe {
+        let wnd_class: &mut [u16] = &mut [0; 512];
+        GetClassNameW(hwnd, wnd_class.as_mut_ptr(), wnd_class.len() as i32 - 1);
+        OsString::from_wide(&wnd_class[..wnd_class.iter().position(|&c| c == 0).unwrap()])
+    };
+
+    wnd_class.to_string_lossy().into_owned()
+}
+
+fn get_window_pid(hwnd: HWND) -> u32 {
+    use winapi::um::winuser::GetWindowThreadProcessId;
+
+    let mut pid = 0;
+    unsafe { GetWindowThreadProcessId(hwnd, &mut pid) };
+    pid
+}
+
+fn get_process_path(pid: u32) -> Option<PathBuf> {
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::processthreadsapi::OpenProcess;
+    use winapi::um::winbase::QueryFullProcessImageNameW;
+    use winapi::um::winnt::PROCESS_QUERY_LIMITED_INFORMATION;
+
+    let path = unsafe {
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if process.is_null() {
+            return None;
+        }
+
+        let path: &mut [u16] = &mut [0; 1024];
+        let mut length = path.len() as u32;
+        let success = QueryFullProcessImageNameW(process, 0, path.as_mut_ptr(), &mut length);
+        CloseHandle(process);
+        if success == 0 {
+            return None;
+        }
+        OsString::from_wide(&path[..length as usize])
+    };
+
+    Some(PathBuf::from(path))
+}
+
+fn get_process_parents() -> HashMap<u32, u32> {
+    use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+    use winapi::um::tlhelp32::{
+        CreateToolhelp32Snapshot, Process32FirstW, Process32NextW, PROCESSENTRY32W, TH32CS_SNAPPROCESS,
+    };
+
+    let mut parents = HashMap::new();
+    unsafe {
+        let snapshot = CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0);
+        if snapshot == INVALID_HANDLE_VALUE {
+            return parents;
+        }
+
+        let mut entry: PROCESSENTRY32W = Default::default();
+        entry.dwSize = std::mem::size_of::<PROCESSENTRY32W>() as u32;
+        let mut found = Process32FirstW(snapshot, &mut entry);
+        while found != 0 {
+            parents.insert(entry.th32ProcessID, entry.th32ParentProcessID);
+            found = Process32NextW(snapshot, &mut entry);
+        }
+
+        CloseHandle(snapshot);
+    }
+    parents
+}
+
+/**
+ * Spawn a wallpaper window if it doesn't already exists and return handle to it.
+ * 
+ * `progman` - a valid handle to the `Progman`.
+ * 
+ * This function is unsafe, because user is responsible for providing valid progman handle.
+ */
+unsafe fn find_or_spawn_worker(progman: HWND) -> Result<HWND, WallpaperError> {
+    use winapi::um::winuser::SendMessageW;
+
+    let mut worker = find_existing_worker()?;
+    if worker.is_null() {
+        // this is basically all the magic. it's an undocumented window message that
+        // forces windows to spawn a window with class "WorkerW" behind deskicons
+        SendMessageW(progman, 0x052C, 0xD, 0);
+        SendMessageW(progman, 0x052C, 0xD, 1);
+
+        worker = find_existing_worker()?;
+        if worker.is_null() {
+            eprintln!("W: couldn't spawn WorkerW window, trying old method");
+
+            SendMessageW(progman, 0x052C, 0, 0);
+
+            worker = find_existing_worker()?;
+        }
+    }
+
+    Ok(worker)
+}
+
+/**
+ * Find the `WorkerW` right behind the window hosting desktop icons (`SHELLDLL_DefView`).
+ *
+ * Returns null handle if there is no such window (yet).
+ */
+unsafe fn find_existing_worker() -> Result<HWND, WallpaperError> {
+    use winapi::um::winuser::EnumWindows;
+
+    extern "system" fn find_worker(hwnd: HWND, data: LPARAM) -> i32 {
+        use winapi::um::winuser::FindWindowExW;
+
+        let data = data as *mut UserData;
+
+        unsafe {
+            if FindWindowExW(hwnd, null_mut(), (*data).shell_class.as_ptr(), null_mut()).is_null() {
+                return 1;
+            }
+            
+            let worker = FindWindowExW(null_mut(), hwnd, (*data).worker_class.as_ptr(), null_mut());
+            if worker.is_null() {
+                return 1;
+            }
+
+            (*data).worker = worker;
+            (*data).parent = hwnd;    
+        }
+
+        return 0;
+    }
+
+    struct UserData {
+        shell_class: Vec<u16>,
+        worker_class: Vec<u16>,
+        worker: HWND,
+        parent: HWND,
+    }
+    
+    let mut user_data = UserData {
+        shell_class: to_wide("SHELLDLL_DefView"),
+        worker_class: to_wide("WorkerW"),
+        worker: null_mut(),
+        parent: null_mut(),
+    };
+
+    SetLastError(0);
+    EnumWindows(Some(find_worker), &mut user_data as *mut UserData as LPARAM);
+    if GetLastError() != 0 {
+        return Err(last_error("EnumWindows"));
+    }
+
+    Ok(user_data.worker)
+}
+
+unsafe fn get_window_style(hwnd: HWND) -> Result<(i32, i32), WallpaperError> {
+    use winapi::um::winuser::{GetWindowLongW, GWL_STYLE, GWL_EXSTYLE};
+
+    SetLastError(0);
+    let style = GetWindowLongW(hwnd, GWL_STYLE);
+    let ex_style = GetWindowLongW(hwnd, GWL_EXSTYLE);
+
+    if (style == 0 || ex_style == 0) && GetLastError() != 0 {
+        return Err(last_error("GetWindowLongW"));
+    }
+
+    Ok((style, ex_style))
+}
+
+unsafe fn set_window_style(wnd: HWND, style: i32, ex_style: i32) -> Result<(), WallpaperError> {
+    use winapi::um::winuser::{SetWindowLongW, GWL_STYLE, GWL_EXSTYLE};
+
+    SetLastError(0);
+    let style = SetWindowLongW(wnd, GWL_STYLE, style);
+    let ex_style = SetWindowLongW(wnd, GWL_EXSTYLE, ex_style);
+    if (style == 0 || ex_style == 0) && GetLastError() != 0 {
+        return Err(last_error("SetWindowLongW"));
+    }
+
+    Ok(())
+}
+
+unsafe fn update_window_styles(
+    wnd: HWND, and: i32, ex_and: i32, or: i32, ex_or: i32
+) -> Result<(), WallpaperError> {
+    let (mut style, mut ex_style) = get_window_style(wnd)?;
+
+    style &= and;
+    ex_style &= ex_and;
+    style |= or;
+    ex_style |= ex_or;
+
+    set_window_style(wnd, style, ex_style)
+}
+
+/// Makes the frame changes done by `SetWindowLongW` visible.
+unsafe fn refresh_frame(wnd: HWND) {
+    use winapi::um::winuser::{SWP_FRAMECHANGED, SWP_NOMOVE, SWP_NOSIZE, SWP_NOZORDER, SWP_NOOWNERZORDER};
+
+    SetWindowPos(
+        wnd, null_mut(), 0, 0, 0, 0, 
+        SWP_FRAMECHANGED | SWP_NOMOVE | SWP_NOSIZE | SWP_NOZORDER | SWP_NOOWNERZORDER
+    );
+}
+
+/// Makes the calling thread work in physical pixels on every monitor until dropped. Only the
+/// geometry queries need that, the web view on the same thread doesn't handle `WM_DPICHANGED`.
+struct PhysicalPixels(DPI_AWARENESS_CONTEXT);
+
+impl PhysicalPixels {
+
+    fn enter() -> PhysicalPixels {
+        use winapi::shared::windef::DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE_V2;
+        use winapi::um::winuser::SetThreadDpiAwarenessContext;
+
+        // fails before Windows 10 1703, which leaves the awareness as it was
+        PhysicalPixels(unsafe { SetThreadDpiAwarenessContext(DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE_V2) })
+    }
+
+}
+
+impl Drop for PhysicalPixels {
+    fn drop(&mut self) {
+        use winapi::um::winuser::SetThreadDpiAwarenessContext;
+
+        if !self.0.is_null() {
+            unsafe { SetThreadDpiAwarenessContext(self.0) };
+        }
+    }
+}
+
+unsafe fn get_window_rect(wnd: HWND) -> Result<RECT, WallpaperError> {
+    let rect: RECT = Default::default();
+    let failed = GetWindowRect(wnd, &rect as *const RECT as *mut RECT) == 0;
+    if failed {
+        return Err(last_error("GetWindowRect"));
+    }
+    Ok(rect)
+}
+
+/// Screen position of the top-left corner of the wallpaper layer. The WorkerW spans the whole
+/// virtual screen, so this is negative when there are monitors left of or above the primary one.
+unsafe fn layer_origin(wallpaper: HWND) -> Result<(i32, i32), WallpaperError> {
+    let rect = get_window_rect(wallpaper)?;
+    Ok((rect.left, rect.top))
+}
+
+/// Position of `wnd` in the coordinates of the wallpaper layer.
+unsafe fn map_window_rect(wallpaper: HWND, wnd: HWND) -> Result<RECT, WallpaperError> {
+    let rect = get_window_rect(wnd)?.into();
+    Ok(geometry::screen_to_layer(rect, layer_origin(wallpaper)?).into())
+}
+
+unsafe fn move_window(wnd: HWND, rect: RECT) -> Result<(), WallpaperError> {
+    use winapi::um::winuser::SWP_NOZORDER;
+
+    // without SWP_NOZORDER the window would also be brought to the top of its siblings
+    let success = SetWindowPos(
+        wnd, null_mut(), rect.left, rect.top, rect.right - rect.left, rect.bottom - rect.top, SWP_NOZORDER
+    );
+    if success == 0 {
+        return Err(last_error("SetWindowPos"));
+    }
+    Ok(())
+}
+
+/// Puts `wnd` on top of its siblings without activating it.
+unsafe fn raise_window(wnd: HWND) -> Result<(), WallpaperError> {
+    use winapi::um::winuser::{HWND_TOP, SWP_NOACTIVATE, SWP_NOMOVE, SWP_NOSIZE};
+
+    if SetWindowPos(wnd, HWND_TOP, 0, 0, 0, 0, SWP_NOACTIVATE | SWP_NOMOVE | SWP_NOSIZE) == 0 {
+        return Err(last_error("SetWindowPos"));
+    }
+    Ok(())
+}
+
+/// Everything `add_window_as_wallpaper` changes about a window.
+#[derive(Debug)]
+pub struct SavedWindow {
+    style: i32,
+    ex_style: i32,
+    parent: HWND,
+    /// Position, size and show state (normal, minimized or maximized).
+    placement: WINDOWPLACEMENT,
+}
+
+unsafe fn save_window(wnd: HWND) -> Result<SavedWindow, WallpaperError> {
+    use winapi::um::winuser::{GetAncestor, GetWindowPlacement, GA_PARENT};
+
+    let (style, ex_style) = get_window_style(wnd)?;
+
+    let mut placement: WINDOWPLACEMENT = Default::default();
+    placement.length = std::mem::size_of::<WINDOWPLACEMENT>() as u32;
+    if GetWindowPlacement(wnd, &mut placement) == 0 {
+        return Err(last_error("GetWindowPlacement"));
+    }
+
+    Ok(SavedWindow { style, ex_style, parent: GetAncestor(wnd, GA_PARENT), placement })
+}
+
+unsafe fn add_window_as_wallpaper(wallpaper: HWND, wnd: HWND) -> Result<SavedWindow, WallpaperError> {
+    use winapi::um::winuser::{
+        SetParent,
+        WS_CHILD, WS_CAPTION, WS_THICKFRAME, WS_SYSMENU, WS_MAXIMIZEBOX, WS_MINIMIZEBOX,
+        WS_EX_DLGMODALFRAME, WS_EX_COMPOSITED, WS_EX_WINDOWEDGE, WS_EX_CLIENTEDGE, WS_EX_LAYERED, WS_EX_STATICEDGE, 
+        WS_EX_TOOLWINDOW, WS_EX_APPWINDOW,
+    };
+
+    /*
+     * styles blacklist taken from https://github.com/Codeusa/Borderless-Gaming/
+     * blob/2fef4ccc121412f215cd7f185c4351fd634cab8b/BorderlessGaming.Logic/
+     * Windows/Manipulation.cs#L70
+     */
+
+    let saved = save_window(wnd)?;
+
+    let and: i32 = !(
+        WS_CAPTION |
+        WS_THICKFRAME |
+        WS_SYSMENU |
+        WS_MAXIMIZEBOX |
+        WS_MINIMIZEBOX
+    ) as i32;
+
+    let ex_and: i32 = !(
+        WS_EX_DLGMODALFRAME |
+        WS_EX_COMPOSITED |
+        WS_EX_WINDOWEDGE |
+        WS_EX_CLIENTEDGE |
+        WS_EX_LAYERED |
+        WS_EX_STATICEDGE |
+        WS_EX_TOOLWINDOW |
+        WS_EX_APPWINDOW
+    ) as i32;
+
+    update_window_styles(wnd, and, ex_and, WS_CHILD as i32, 0)?;
+
+    /* window retains screen coordinates so we need to adjust them */
+    let reparented = map_window_rect(wallpaper, wnd).and_then(|rect| {
+        if SetParent(wnd, wallpaper).is_null() {
+            return Err(WallpaperError::ReparentFailed { os_code: GetLastError() });
+        }
+        Ok(rect)
+    });
+    let rect = match reparented {
+        Ok(rect) => rect,
+        Err(error) => {
+            /* left with WS_CHILD, the top-level window could no longer be activated */
+            let _ = set_window_style(wnd, saved.style, saved.ex_style);
+            return Err(error);
+        },
+    };
+    move_window(wnd, rect)?;
+    ShowWindow(wnd, SW_SHOW);
+
+    Ok(saved)
+}
+
+unsafe fn remove_window_from_wallpaper(
+    wallpaper: HWND, wnd: HWND, saved: Option<SavedWindow>
+) -> Result<(), WallpaperError> {
+    use winapi::um::winuser::{
+        SetParent, GetDesktopWindow, InvalidateRect, SetWindowPlacement,
+        WS_EX_APPWINDOW, WS_OVERLAPPEDWINDOW, 
+    };
+
+    let parent = saved.as_ref().map(|saved| saved.parent).unwrap_or_else(|| GetDesktopWindow());
+    if SetParent(wnd, parent).is_null() {
+        return Err(WallpaperError::ReparentFailed { os_code: GetLastError() });
+    }
+
+    match saved {
+        Some(saved) => {
+            set_window_style(wnd, saved.style, saved.ex_style)?;
+            refresh_frame(wnd);
+            if SetWindowPlacement(wnd, &saved.placement) == 0 {
+                return Err(last_error("SetWindowPlacement"));
+            }
+        },
+        None => {
+            /* window was attached by someone else, best we can do is give it a regular frame */
+            let or = WS_OVERLAPPEDWINDOW as i32;
+            let ex_or = WS_EX_APPWINDOW as i32;
+
+            update_window_styles(wnd, -1, -1, or, ex_or)?;
+            refresh_frame(wnd);
+        },
+    }
+
+    InvalidateRect(wallpaper, null_mut(), 1);
+    // wp_id(); /* can sometimes fix leftover unrefreshed portions */
+
+    Ok(())
+}
+
+unsafe fn get_monitor_info(monitor: HMONITOR) -> Result<Monitor, WallpaperError> {
+    use winapi::shared::winerror::S_OK;
+    use winapi::um::shellscalingapi::{GetDpiForMonitor, MDT_EFFECTIVE_DPI};
+    use winapi::um::winuser::{MONITORINFOEXW, MONITORINFOF_PRIMARY};
+
+    let mut mi: MONITORINFOEXW = Default::default();
+    mi.cbSize = std::mem::size_of::<MONITORINFOEXW>() as u32;
+    let success = GetMonitorInfoW(monitor, &mut mi as *mut MONITORINFOEXW as *mut MONITORINFO);
+    if success == 0 {
+        return Err(last_error("GetMonitorInfoW"));
+    }
+
+    let (mut dpi, mut dpi_y) = (0, 0);
+    if GetDpiForMonitor(monitor, MDT_EFFECTIVE_DPI, &mut dpi, &mut dpi_y) != S_OK || dpi == 0 {
+        dpi = DEFAULT_DPI;
+    }
+
+    let name_length = mi.szDevice.iter().position(|&c| c == 0).unwrap_or(mi.szDevice.len());
+    Ok(Monitor {
+        name: OsString::from_wide(&mi.szDevice[..name_length]).to_string_lossy().into_owned(),
+        primary: mi.dwFlags & MONITORINFOF_PRIMARY != 0,
+        rect: mi.rcMonitor.into(),
+        work_area: mi.rcWork.into(),
+        dpi,
+    })
+}
+
+unsafe fn get_monitor_at(point: POINT) -> Result<Monitor, WallpaperError> {
+    let monitor = MonitorFromPoint(point, MONITOR_DEFAULTTONEAREST);
+    if monitor.is_null() {
+        return Err(last_error("MonitorFromPoint"));
+    }
+    get_monitor_info(monitor)
+}
+
+unsafe fn get_monitors() -> Result<Vec<Monitor>, WallpaperError> {
+    use winapi::um::winuser::EnumDisplayMonitors;
+
+    extern "system" fn enum_monitors(monitor: HMONITOR, _dc: HDC, _rect: LPRECT, lp: LPARAM) -> BOOL {
+        let handles = lp as *mut Vec<HMONITOR>;
+        unsafe { (*handles).push(monitor) };
+        1
+    }
+
+    let mut handles: Vec<HMONITOR> = Vec::new();
+    let success = EnumDisplayMonitors(
+        null_mut(), null_mut(), Some(enum_monitors), &mut handles as *mut Vec<HMONITOR> as LPARAM
+    );
+    if success == 0 {
+        return Err(last_error("EnumDisplayMonitors"));
+    }
+
+    handles.into_iter().map(|monitor| get_monitor_info(monitor)).collect()
+}
+
+unsafe fn list_immediate_children(parent: HWND) -> Result<Vec<HWND>, WallpaperError> {
+    use winapi::um::winuser::EnumChildWindows;
+
+    #[repr(C)]
+    struct WindowState {
+        parent: HWND,
+        handles: Vec<HWND>,
+    }
+    
+    let mut s = WindowState { parent, handles: Vec::new() };
+
+    extern "system" fn enum_windows(wnd: HWND, lp: LPARAM) -> i32 {
+        use winapi::um::winuser::{GetAncestor, GA_PARENT};
+
+        let s: *mut WindowState = lp as *mut WindowState;
+        
+        unsafe {
+            if GetAncestor(wnd, GA_PARENT) == (*s).parent {
+                (*s).handles.push(wnd);
+            }
+        }
+        
+        return 1;
+    }
+
+    SetLastError(0);
+    EnumChildWindows(parent, Some(enum_windows), &mut s as *mut WindowState as LPARAM);
+    if GetLastError() != 0 {
+        return Err(last_error("EnumChildWindows"));
+    }
+
+    // children are enumerated from the top of the z-order down
+    s.handles.reverse();
+
+    Ok(s.handles)
+}
+
+thread_local! {
+    /// Where `on_window_event` reports changes, set on the watcher thread only.
+    static WINDOW_CHANGES: std::cell::RefCell<Option<Sender<()>>> = std::cell::RefCell::new(None);
+    /// Where `on_watcher_message` reports display changes, set on the watcher thread only.
+    static DISPLAY_CHANGES: std::cell::RefCell<Option<Arc<AtomicBool>>> = std::cell::RefCell::new(None);
+}
+
+unsafe extern "system" fn on_window_event(
+    _hook: HWINEVENTHOOK, _event: DWORD, hwnd: HWND, id_object: LONG, _id_child: LONG,
+    _thread: DWORD, _time: DWORD,
+) {
+    use winapi::um::winuser::{GetAncestor, GA_ROOT, OBJID_WINDOW};
+
+    /* only top-level windows themselves, not their controls, carets, scrollbars, etc. */
+    if hwnd.is_null() || id_object != OBJID_WINDOW || GetAncestor(hwnd, GA_ROOT) != hwnd {
+        return;
+    }
+
+    WINDOW_CHANGES.with(|changes| {
+        if let Some(sender) = &*changes.borrow() {
+            let _ = sender.send(());
+        }
+    });
+}
+
+unsafe extern "system" fn on_watcher_message(hwnd: HWND, msg: UINT, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    use winapi::um::winuser::{DefWindowProcW, SPI_SETWORKAREA, WM_DISPLAYCHANGE, WM_DPICHANGED, WM_SETTINGCHANGE};
+
+    let changed = match msg {
+        WM_DISPLAYCHANGE | WM_DPICHANGED => true,
+        WM_SETTINGCHANGE => wparam == SPI_SETWORKAREA as WPARAM,
+        _ => false,
+    };
+    if changed {
+        DISPLAY_CHANGES.with(|changes| {
+            if let Some(flag) = &*changes.borrow() {
+                flag.store(true, Ordering::SeqCst);
+            }
+        });
+    }
+
+    DefWindowProcW(hwnd, msg, wparam, lparam)
+}
+
+/**
+ * Creates the hidden top-level window which receives display change broadcasts. Message-only
+ * windows don't get broadcasts, so it is a regular window which is never shown.
+ */
+unsafe fn create_watcher_window() -> HWND {
+    use winapi::um::libloaderapi::GetModuleHandleW;
+    use winapi::um::winuser::{CreateWindowExW, RegisterClassW, WNDCLASSW};
+
+    let class_name = to_wide("WallpaperRsWatcher");
+    let class = WNDCLASSW {
+        lpfnWndProc: Some(on_watcher_message),
+        hInstance: GetModuleHandleW(null_mut()),
+        lpszClassName: class_name.as_ptr(),
+        ..Default::default()
+    };
+    RegisterClassW(&class);
+    CreateWindowExW(
+        0, class_name.as_ptr(), null_mut(), 0, 0, 0, 0, 0, null_mut(), null_mut(), class.hInstance, null_mut(),
+    )
+}
+
+/**
+ * Watches top-level windows being created, shown or renamed and displays being changed for as
+ * long as it lives.
+ *
+ * Out-of-context WinEvent hooks and messages of a window are delivered through the message loop
+ * of the thread which set or created them, so that is a thread of our own instead of the UI
+ * thread.
+ */
+#[derive(Debug)]
+struct DesktopWatcher {
+    thread_id: DWORD,
+    changed: Receiver<()>,
+    /// Missing if the window receiving display changes couldn't be created.
+    displays_changed: Option<Arc<AtomicBool>>,
+}
+
+impl DesktopWatcher {
+
+    /// Starts the watcher thread and sets the hooks. Returns `None` if they can't be set.
+    fn start() -> Option<DesktopWatcher> {
+        use winapi::um::processthreadsapi::GetCurrentThreadId;
+        use winapi::um::winuser::{
+            SetWinEventHook, UnhookWinEvent, GetMessageW, PeekMessageW, TranslateMessage, DispatchMessageW,
+            DestroyWindow,
+            EVENT_OBJECT_CREATE, EVENT_OBJECT_SHOW, EVENT_OBJECT_NAMECHANGE,
+            WINEVENT_OUTOFCONTEXT, WINEVENT_SKIPOWNPROCESS, PM_NOREMOVE, WM_USER, MSG,
+        };
+
+        let (changes, changed) = channel();
+        let displays_changed = Arc::new(AtomicBool::new(false));
+        let display_changes = Arc::clone(&displays_changed);
+        let (started_sender, started) = channel();
+
+        std::thread::spawn(move || unsafe {
+            let mut msg: MSG = Default::default();
+            /* makes sure the thread has a message queue before anyone posts to it */
+            PeekMessageW(&mut msg, null_mut(), WM_USER, WM_USER, PM_NOREMOVE);
+            WINDOW_CHANGES.with(|sender| *sender.borrow_mut() = Some(changes));
+            DISPLAY_CHANGES.with(|flag| *flag.borrow_mut() = Some(display_changes));
+            /* windows only get WM_DPICHANGED if their thread was per-monitor aware when creating them */
+            std::mem::forget(PhysicalPixels::enter());
+            let window = create_watcher_window();
+
+            let flags = WINEVENT_OUTOFCONTEXT | WINEVENT_SKIPOWNPROCESS;
+            /* one hook per event, the range between CREATE and SHOW includes DESTROY */
+            let hook = |event| SetWinEventHook(event, event, null_mut(), Some(on_window_event), 0, 0, flags);
+            let hooks = [hook(EVENT_OBJECT_CREATE), hook(EVENT_OBJECT_SHOW), hook(EVENT_OBJECT_NAMECHANGE)];
+            let hooked = hooks.iter().all(|hook| !hook.is_null());
+            let _ = started_sender.send(if hooked { Some((GetCurrentThreadId(), !window.is_null())) } else { None });
+
+            while hooked && GetMessageW(&mut msg, null_mut(), 0, 0) > 0 {
+                TranslateMessage(&msg);
+                DispatchMessageW(&msg);
+            }
+
+            for &hook in hooks.iter().filter(|hook| !hook.is_null()) {
+                UnhookWinEvent(hook);
+            }
+            if !window.is_null() {
+                DestroyWindow(window);
+            }
+        });
+
+        let (thread_id, has_window) = started.recv().ok()??;
+        let displays_changed = if has_window { Some(displays_changed) } else { None };
+        Some(DesktopWatcher { thread_id, changed, displays_changed })
+    }
+
+    /// Blocks until a change is reported or `timeout` passes. Changes reported before the call
+    /// are dropped, otherwise any window renamed since the previous wait would skip the delay.
+    fn wait(&self, timeout: Duration) {
+        while self.changed.try_recv().is_ok() {}
+        let _ = self.changed.recv_timeout(timeout);
+    }
+
+    /// Whether displays were reported to change since the previous call. Without the window
+    /// receiving the reports they may always have changed.
+    fn displays_changed(&self) -> bool {
+        self.displays_changed.as_ref().map_or(true, |flag| flag.swap(false, Ordering::SeqCst))
+    }
+
+}
+
+impl Drop for DesktopWatcher {
+    fn drop(&mut self) {
+        use winapi::um::winuser::{PostThreadMessageW, WM_QUIT};
+        unsafe { PostThreadMessageW(self.thread_id, WM_QUIT, 0, 0) };
+    }
+}
+
+fn list_windows() -> Result<Vec<HWND>, WallpaperError> {
+    use winapi::um::winuser::{
+        EnumWindows, IsWindowVisible, GetLastActivePopup, GetAncestor, GetWindowTextLengthW, 
+        GA_ROOTOWNER, WS_EX_NOREDIRECTIONBITMAP, WS_EX_TOOLWINDOW
+    };
+
+    // https://stackoverflow.com/questions/210504/enumerate-windows-like-alt-tab-does
+    unsafe fn should_list(hwnd: HWND) -> bool {
+        // Start at the root owner
+        let mut hwnd_walk = GetAncestor(hwnd, GA_ROOTOWNER);
+        // See if we are the last active visible popup
+        let mut hwnd_try = null_mut();
+        loop {
+            let hwnd_try_next = GetLastActivePopup(hwnd_walk);
+            if hwnd_try_next == hwnd_try || IsWindowVisible(hwnd_try_next) == 1 {
+                break;
+            }
+            hwnd_try = hwnd_try_next;
+            hwnd_walk = hwnd_try;
+        }
+
+        return hwnd_walk == hwnd;
+    }
+
+    extern "system" fn list_windows_callback(hwnd: HWND, lp: LPARAM) -> i32 {
+        let data = lp as *mut Vec<HWND>;
+
+        unsafe {
+            if  IsWindowVisible(hwnd) == 1 && GetWindowTextLengthW(hwnd) > 0 && should_list(hwnd) {
+                let ex_style = get_window_style(hwnd).map(|(_, ex_style)| ex_style).unwrap_or(0);
+                if (ex_style as u32 & WS_EX_NOREDIRECTIONBITMAP) == 0 && (ex_style as u32 & WS_EX_TOOLWINDOW) == 0 {
+                    (*data).push(hwnd);
+                }
+            }
+        }
+
+        1
+    }
+
+    let mut data: Vec<HWND> = Vec::new();
+
+    unsafe {
+        SetLastError(0);
+        EnumWindows(Some(list_windows_callback), &mut data as *mut Vec<HWND> as LPARAM);
+        if GetLastError() != 0 {
+            return Err(last_error("EnumWindows"));
+        }
+    }
+
+    Ok(data)
+}
+
+fn to_hwnd(window: WindowId) -> HWND {
+    window.0 as usize as HWND
+}
+
+fn to_window_id(hwnd: HWND) -> WindowId {
+    WindowId(hwnd as usize as u64)
+}
+
+impl From<RECT> for Rect {
+    fn from(rect: RECT) -> Rect {
+        Rect { left: rect.left, top: rect.top, right: rect.right, bottom: rect.bottom }
+    }
+}
+
+impl From<Rect> for RECT {
+    fn from(rect: Rect) -> RECT {
+        RECT { left: rect.left, top: rect.top, right: rect.right, bottom: rect.bottom }
+    }
+}
+
+/// Finds `Progman` and the `WorkerW` behind desktop icons, spawning the latter if needed.
+fn find_desktop() -> Result<(HWND, HWND), WallpaperError> {
+    let progman_handle = find_window_by_class("Progman");
+    if progman_handle.is_null() {
+        return Err(WallpaperError::ProgmanNotFound);
+    }
+
+    let worker_handle = unsafe { find_or_spawn_worker(progman_handle)? };
+    if worker_handle.is_null() {
+        return Err(WallpaperError::UnableToSpawnWorker);
+    }
+
+    Ok((progman_handle, worker_handle))
+}
+
+/// Win32 backend which places wallpapers into the `WorkerW` window spawned by `Progman`.
+#[derive(Debug)]
+pub struct WindowsBackend {
+    progman: Cell<HWND>,
+    worker: Cell<HWND>,
+    /// Missing if the hooks couldn't be set, waiting for changes falls back to sleeping then.
+    watcher: Option<DesktopWatcher>,
+}
+
+impl WindowsBackend {
+
+    pub fn new() -> Result<WindowsBackend, WallpaperError> {
+        let (progman, worker) = find_desktop()?;
+        Ok(WindowsBackend {
+            progman: Cell::new(progman),
+            worker: Cell::new(worker),
+            watcher: DesktopWatcher::start(),
+        })
+    }
+
+}
+
+impl DesktopBackend for WindowsBackend {
+
+    const FORBIDDEN_CLASSES: &'static [&'static str] = &["Shell_TrayWnd"];
+
+    type SavedWindow = SavedWindow;
+
+    fn list_windows(&self) -> Result<Vec<WindowId>, WallpaperError> {
+        Ok(list_windows()?.into_iter().map(to_window_id).collect())
+    }
+
+    fn list_attached(&self) -> Result<Vec<WindowId>, WallpaperError> {
+        unsafe {
+            Ok(list_immediate_children(self.worker.get())?.into_iter().map(to_window_id).collect())
+        }
+    }
+
+    fn window_exists(&self, window: WindowId) -> bool {
+        use winapi::um::winuser::IsWindow;
+        unsafe { IsWindow(to_hwnd(window)) != 0 }
+    }
+
+    fn window_title(&self, window: WindowId) -> String {
+        get_window_name(to_hwnd(window))
+    }
+
+    fn window_class(&self, window: WindowId) -> String {
+        get_window_class(to_hwnd(window))
+    }
+
+    fn window_pid(&self, window: WindowId) -> u32 {
+        get_window_pid(to_hwnd(window))
+    }
+
+    fn process_path(&self, pid: u32) -> Option<PathBuf> {
+        get_process_path(pid)
+    }
+
+    fn process_parents(&self) -> HashMap<u32, u32> {
+        get_process_parents()
+    }
+
+    fn window_rect(&self, window: WindowId) -> Result<Rect, WallpaperError> {
+        let _pixels = PhysicalPixels::enter();
+        unsafe { get_window_rect(to_hwnd(window)) }.map(Rect::from)
+    }
+
+    fn is_layer_valid(&self) -> bool {
+        use winapi::um::winuser::IsWindow;
+        unsafe {
+            /* explorer.exe may recreate the icons and leave our worker behind, or in front of them */
+            IsWindow(self.progman.get()) != 0 && IsWindow(self.worker.get()) != 0
+                && find_existing_worker().map(|worker| worker == self.worker.get()).unwrap_or(false)
+        }
+    }
+
+    fn reacquire_layer(&self) -> Result<(), WallpaperError> {
+        let (progman, worker) = find_desktop()?;
+        self.progman.set(progman);
+        self.worker.set(worker);
+        Ok(())
+    }
+
+    fn is_wallpaper_layer(&self, window: WindowId) -> bool {
+        to_hwnd(window) == self.worker.get()
+    }
+
+    fn is_attached(&self, window: WindowId) -> bool {
+        unsafe { IsChild(self.worker.get(), to_hwnd(window)) != 0 }
+    }
+
+    fn attach(&self, window: WindowId) -> Result<SavedWindow, WallpaperError> {
+        let _pixels = PhysicalPixels::enter();
+        unsafe { add_window_as_wallpaper(self.worker.get(), to_hwnd(window)) }
+    }
+
+    fn detach(&self, window: WindowId, saved: Option<SavedWindow>) -> Result<(), WallpaperError> {
+        let _pixels = PhysicalPixels::enter();
+        unsafe { remove_window_from_wallpaper(self.worker.get(), to_hwnd(window), saved) }
+    }
+
+    fn close_window(&self, window: WindowId) {
+        use winapi::um::winuser::{SendMessageW, WM_CLOSE};
+        unsafe { SendMessageW(to_hwnd(window), WM_CLOSE, 0, 0) };
+    }
+
+    fn move_window(&self, window: WindowId, rect: Rect) -> Result<(), WallpaperError> {
+        let _pixels = PhysicalPixels::enter();
+        unsafe { move_window(to_hwnd(window), rect.into()) }
+    }
+
+    fn restack(&self, windows: &[WindowId]) -> Result<(), WallpaperError> {
+        for &window in windows {
+            unsafe { raise_window(to_hwnd(window))?; }
+        }
+        Ok(())
+    }
+
+    fn map_to_layer(&self, rect: Rect) -> Rect {
+        let _pixels = PhysicalPixels::enter();
+        let origin = unsafe { layer_origin(self.worker.get()) }.unwrap_or((0, 0));
+        geometry::screen_to_layer(rect, origin)
+    }
+
+    fn monitors(&self) -> Result<Vec<Monitor>, WallpaperError> {
+        let _pixels = PhysicalPixels::enter();
+        unsafe { get_monitors() }
+    }
+
+    fn monitor_at(&self, x: i32, y: i32) -> Result<Monitor, WallpaperError> {
+        let _pixels = PhysicalPixels::enter();
+        unsafe { get_monitor_at(POINT {x, y}) }
+    }
+
+    fn displays_changed(&self) -> bool {
+        self.watcher.as_ref().map_or(true, DesktopWatcher::displays_changed)
+    }
+
+    fn wait_for_changes(&self, timeout: Duration) -> Duration {
+        let start = Instant::now();
+        match &self.watcher {
+            Some(watcher) => watcher.wait(timeout),
+            None => std::thread::sleep(timeout),
+        }
+        start.elapsed()
+    }
+
+    fn redraw(&self) {
+        use winapi::um::winuser::InvalidateRect;
+        unsafe { InvalidateRect(null_mut(), null_mut(), 1) };
+    }
+
+}
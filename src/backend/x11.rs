@@ -0,0 +1,624 @@
+//! X11 backend.
+//!
+//! There is no `WorkerW` on X11, so we create our own wallpaper layer: a screen-sized window of
+//! type `_NET_WM_WINDOW_TYPE_DESKTOP` which is sticky, below everything else, hidden from
+//! taskbars and pagers, and stacked under desktop icons (if any). Client windows are reparented
+//! into this layer, which makes window managers stop managing them, much like `SetParent`
+//! into `WorkerW` does on Windows.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use x11rb::connection::Connection;
+use x11rb::errors::ReplyError;
+use x11rb::properties::WmClass;
+use x11rb::protocol::randr::{ConnectionExt as _, NotifyMask};
+use x11rb::protocol::xproto::{
+    Atom, AtomEnum, BackPixmap, ChangeWindowAttributesAux, ClientMessageEvent, CloseDown, ConfigureWindowAux,
+    ConnectionExt as _, CreateWindowAux, EventMask, MapState, PropMode, StackMode, Window, WindowClass,
+};
+use x11rb::protocol::Event;
+use x11rb::rust_connection::RustConnection;
+use x11rb::wrapper::ConnectionExt as _;
+use x11rb::{COPY_DEPTH_FROM_PARENT, CURRENT_TIME};
+
+use crate::geometry::{self, DEFAULT_DPI};
+use crate::wallpaper::WallpaperError;
+
+use super::{proc_executable, proc_parents, DesktopBackend, Monitor, Rect, WindowId};
+
+x11rb::atom_manager! {
+    Atoms: AtomsCookie {
+        UTF8_STRING,
+        WM_PROTOCOLS,
+        WM_DELETE_WINDOW,
+        _NET_CLIENT_LIST,
+        _NET_WORKAREA,
+        _NET_WM_NAME,
+        _NET_WM_PID,
+        _NET_WM_DESKTOP,
+        _NET_WM_WINDOW_TYPE,
+        _NET_WM_WINDOW_TYPE_DESKTOP,
+        _NET_WM_WINDOW_TYPE_DOCK,
+        _NET_WM_WINDOW_TYPE_TOOLBAR,
+        _NET_WM_WINDOW_TYPE_MENU,
+        _NET_WM_WINDOW_TYPE_UTILITY,
+        _NET_WM_WINDOW_TYPE_SPLASH,
+        _NET_WM_WINDOW_TYPE_DIALOG,
+        _NET_WM_WINDOW_TYPE_NORMAL,
+        _NET_WM_STATE,
+        _NET_WM_STATE_BELOW,
+        _NET_WM_STATE_STICKY,
+        _NET_WM_STATE_SKIP_TASKBAR,
+        _NET_WM_STATE_SKIP_PAGER,
+        _WALLPAPER_RS_LAYER,
+    }
+}
+
+/// Events always selected on the root window: property changes tell when `_NET_WORKAREA` changes.
+const ROOT_EVENTS: EventMask = EventMask::PROPERTY_CHANGE;
+
+/// `WM_CLASS` of the wallpaper layer window, which tells it apart in tools like `xprop`.
+const LAYER_INSTANCE: &[u8] = b"wallpaper-rs";
+const LAYER_CLASS: &[u8] = b"WallpaperLayer";
+
+/// How deep below the root window the layer is looked for. Reparenting window managers put it
+/// into a frame, some of them into a frame inside another one.
+const LAYER_SEARCH_DEPTH: usize = 3;
+
+fn to_window(window: WindowId) -> Window {
+    window.0 as Window
+}
+
+fn to_window_id(window: Window) -> WindowId {
+    WindowId(window as u64)
+}
+
+fn os_code(error: ReplyError) -> u32 {
+    match error {
+        ReplyError::X11Error(error) => error.error_code as u32,
+        ReplyError::ConnectionError(_) => 0,
+    }
+}
+
+/// Builds an error for the failed X11 request `call`.
+fn request_failed<E: Into<ReplyError>>(call: &'static str) -> impl FnOnce(E) -> WallpaperError {
+    move |error| WallpaperError::Os { call, os_code: os_code(error.into()) }
+}
+
+fn get_property32(conn: &RustConnection, window: Window, property: Atom, type_: Atom) -> Vec<u32> {
+    conn.get_property(false, window, property, type_, 0, u32::MAX).ok()
+        .and_then(|cookie| cookie.reply().ok())
+        .and_then(|reply| reply.value32().map(|value| value.collect()))
+        .unwrap_or_default()
+}
+
+fn get_property_string(conn: &RustConnection, window: Window, property: Atom, type_: Atom) -> Option<String> {
+    conn.get_property(false, window, property, type_, 0, u32::MAX).ok()
+        .and_then(|cookie| cookie.reply().ok())
+        .filter(|reply| reply.format == 8 && reply.type_ == type_)
+        .map(|reply| String::from_utf8_lossy(&reply.value).into_owned())
+}
+
+/// DPI set as `Xft.dpi` in the X resources, which desktop environments use as a single scale
+/// factor for all monitors. Only a fallback for monitors which don't report their physical size.
+fn get_xft_dpi(conn: &RustConnection, root: Window) -> Option<u32> {
+    let resources = get_property_string(conn, root, AtomEnum::RESOURCE_MANAGER.into(), AtomEnum::STRING.into())?;
+    resources.lines()
+        .filter_map(|line| line.strip_prefix("Xft.dpi:"))
+        .filter_map(|value| value.trim().parse::<f64>().ok())
+        .map(|dpi| dpi.round() as u32)
+        .find(|&dpi| dpi > 0)
+}
+
+fn get_children(conn: &RustConnection, window: Window) -> Vec<Window> {
+    conn.query_tree(window).ok()
+        .and_then(|cookie| cookie.reply().ok())
+        .map(|reply| reply.children)
+        .unwrap_or_default()
+}
+
+fn get_parent(conn: &RustConnection, window: Window) -> Option<Window> {
+    conn.query_tree(window).ok()
+        .and_then(|cookie| cookie.reply().ok())
+        .map(|reply| reply.parent)
+}
+
+fn get_window_rect(conn: &RustConnection, root: Window, window: Window) -> Option<Rect> {
+    let geometry = conn.get_geometry(window).ok()?.reply().ok()?;
+    let origin = conn.translate_coordinates(window, root, 0, 0).ok()?.reply().ok()?;
+    let (left, top) = (origin.dst_x as i32, origin.dst_y as i32);
+    Some(Rect {
+        left,
+        top,
+        right: left + geometry.width as i32,
+        bottom: top + geometry.height as i32,
+    })
+}
+
+/// Blocks until `fd` has data to read or `timeout` passes.
+fn wait_readable(fd: RawFd, timeout: Duration) {
+    let mut pollfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    // rounded up, so a fraction of a millisecond left doesn't turn into a busy loop
+    let millis = timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32;
+    // SAFETY: `pollfd` outlives the call, interruptions are handled by the caller's loop
+    unsafe { libc::poll(&mut pollfd, 1, millis) };
+}
+
+/// Whether `window` is a wallpaper layer, which carries the private `_WALLPAPER_RS_LAYER` property.
+fn is_layer(conn: &RustConnection, atoms: &Atoms, window: Window) -> bool {
+    !get_property32(conn, window, atoms._WALLPAPER_RS_LAYER, AtomEnum::CARDINAL.into()).is_empty()
+}
+
+/// Finds a layer spawned by this or an earlier run, in the client list or wherever the window
+/// manager put it in the window tree.
+fn find_layer(conn: &RustConnection, root: Window, atoms: &Atoms) -> Option<Window> {
+    let clients = get_property32(conn, root, atoms._NET_CLIENT_LIST, AtomEnum::WINDOW.into());
+    if let Some(layer) = clients.into_iter().find(|&window| is_layer(conn, atoms, window)) {
+        return Some(layer);
+    }
+
+    let mut pending: Vec<(Window, usize)> = get_children(conn, root).into_iter().map(|window| (window, 1)).collect();
+    while let Some((window, depth)) = pending.pop() {
+        if is_layer(conn, atoms, window) {
+            return Some(window);
+        }
+        if depth < LAYER_SEARCH_DEPTH {
+            pending.extend(get_children(conn, window).into_iter().map(|child| (child, depth + 1)));
+        }
+    }
+    None
+}
+
+/**
+ * Creates the wallpaper layer window on a separate connection.
+ *
+ * The connection is closed in `RetainPermanent` mode, so the layer outlives this process the same
+ * way `WorkerW` does, and the next run finds it by its `_WALLPAPER_RS_LAYER` property instead of
+ * creating another one.
+ */
+fn spawn_layer(atoms: &Atoms) -> Option<Window> {
+    let (conn, screen_num) = x11rb::connect(None).ok()?;
+    let screen = &conn.setup().roots[screen_num];
+    let layer = conn.generate_id().ok()?;
+
+    conn.set_close_down_mode(CloseDown::RETAIN_PERMANENT).ok()?;
+    conn.create_window(
+        COPY_DEPTH_FROM_PARENT, layer, screen.root,
+        0, 0, screen.width_in_pixels, screen.height_in_pixels, 0,
+        WindowClass::INPUT_OUTPUT, screen.root_visual,
+        // show whatever is set as the root window background
+        &CreateWindowAux::new().background_pixmap(u32::from(BackPixmap::PARENT_RELATIVE)),
+    ).ok()?;
+
+    let mut wm_class = Vec::new();
+    wm_class.extend_from_slice(LAYER_INSTANCE);
+    wm_class.push(0);
+    wm_class.extend_from_slice(LAYER_CLASS);
+    wm_class.push(0);
+
+    conn.change_property8(PropMode::REPLACE, layer, AtomEnum::WM_CLASS, AtomEnum::STRING, &wm_class).ok()?;
+    conn.change_property32(PropMode::REPLACE, layer, atoms._WALLPAPER_RS_LAYER, AtomEnum::CARDINAL, &[1]).ok()?;
+    conn.change_property32(
+        PropMode::REPLACE, layer, atoms._NET_WM_WINDOW_TYPE, AtomEnum::ATOM,
+        &[atoms._NET_WM_WINDOW_TYPE_DESKTOP],
+    ).ok()?;
+    conn.change_property32(
+        PropMode::REPLACE, layer, atoms._NET_WM_STATE, AtomEnum::ATOM,
+        &[
+            atoms._NET_WM_STATE_BELOW,
+            atoms._NET_WM_STATE_STICKY,
+            atoms._NET_WM_STATE_SKIP_TASKBAR,
+            atoms._NET_WM_STATE_SKIP_PAGER,
+        ],
+    ).ok()?;
+    // 0xFFFFFFFF means "show on all desktops"
+    conn.change_property32(
+        PropMode::REPLACE, layer, atoms._NET_WM_DESKTOP, AtomEnum::CARDINAL, &[0xFFFF_FFFF],
+    ).ok()?;
+
+    conn.map_window(layer).ok()?;
+    // sibling-less Below puts the layer at the very bottom, i.e. under desktop icons
+    conn.configure_window(layer, &ConfigureWindowAux::new().stack_mode(StackMode::BELOW)).ok()?;
+    conn.get_input_focus().ok()?.reply().ok()?;
+
+    Some(layer)
+}
+
+fn find_or_spawn_layer(conn: &RustConnection, root: Window, atoms: &Atoms) -> Option<Window> {
+    find_layer(conn, root, atoms).or_else(|| spawn_layer(atoms).and_then(|_| find_layer(conn, root, atoms)))
+}
+
+/// State of a window before it was reparented into the wallpaper layer.
+///
+/// The parent is not recorded: top-level windows are children of either the root window or a
+/// window manager frame, and the frame is destroyed as soon as the window leaves it, so windows
+/// always go back to the root window where the window manager reframes them.
+#[derive(Debug)]
+pub struct SavedWindow {
+    /// Window rect in root coordinates.
+    rect: Rect,
+    mapped: bool,
+}
+
+/// X11 backend which places wallpapers into a desktop-type window below desktop icons.
+#[derive(Debug)]
+pub struct X11Backend {
+    conn: RustConnection,
+    root: Window,
+    layer: Cell<Window>,
+    atoms: Atoms,
+    /// Set when RandR or the work area reported a change `displays_changed` didn't return yet.
+    displays_changed: Cell<bool>,
+}
+
+impl X11Backend {
+
+    pub fn new() -> Result<X11Backend, WallpaperError> {
+        let (conn, screen_num) = x11rb::connect(None).map_err(|_| WallpaperError::DisplayNotFound)?;
+        let root = conn.setup().roots[screen_num].root;
+
+        let atoms = Atoms::new(&conn).ok()
+            .and_then(|cookie| cookie.reply().ok())
+            .ok_or(WallpaperError::DisplayNotFound)?;
+
+        let layer = find_or_spawn_layer(&conn, root, &atoms).ok_or(WallpaperError::UnableToSpawnWorker)?;
+
+        // unlike window changes, display changes are watched all the time
+        let _ = conn.randr_select_input(
+            root, NotifyMask::SCREEN_CHANGE | NotifyMask::CRTC_CHANGE | NotifyMask::OUTPUT_CHANGE,
+        );
+        let _ = conn.change_window_attributes(root, &ChangeWindowAttributesAux::new().event_mask(ROOT_EVENTS));
+        let _ = conn.flush();
+
+        Ok(X11Backend { conn, root, layer: Cell::new(layer), atoms, displays_changed: Cell::new(false) })
+    }
+
+    fn client_list(&self) -> Vec<Window> {
+        get_property32(&self.conn, self.root, self.atoms._NET_CLIENT_LIST, AtomEnum::WINDOW.into())
+    }
+
+    /// Mirrors the Win32 task switcher filter: normal, titled windows not hidden from the taskbar.
+    fn should_list(&self, window: Window) -> bool {
+        // types are listed by preference, the first standard one wins over vendor types like
+        // _KDE_NET_WM_WINDOW_TYPE_OVERRIDE; windows without one are normal
+        let atoms = &self.atoms;
+        let standard = [
+            atoms._NET_WM_WINDOW_TYPE_DESKTOP, atoms._NET_WM_WINDOW_TYPE_DOCK, atoms._NET_WM_WINDOW_TYPE_TOOLBAR,
+            atoms._NET_WM_WINDOW_TYPE_MENU, atoms._NET_WM_WINDOW_TYPE_UTILITY, atoms._NET_WM_WINDOW_TYPE_SPLASH,
+            atoms._NET_WM_WINDOW_TYPE_DIALOG, atoms._NET_WM_WINDOW_TYPE_NORMAL,
+        ];
+        let types = get_property32(&self.conn, window, atoms._NET_WM_WINDOW_TYPE, AtomEnum::ATOM.into());
+        let window_type = types.into_iter().find(|window_type| standard.contains(window_type));
+        if window_type.unwrap_or(atoms._NET_WM_WINDOW_TYPE_NORMAL) != atoms._NET_WM_WINDOW_TYPE_NORMAL {
+            return false;
+        }
+
+        let state = get_property32(&self.conn, window, self.atoms._NET_WM_STATE, AtomEnum::ATOM.into());
+        if state.contains(&self.atoms._NET_WM_STATE_SKIP_TASKBAR) {
+            return false;
+        }
+
+        !self.window_title(to_window_id(window)).is_empty()
+    }
+
+    fn layer_origin(&self) -> (i32, i32) {
+        get_window_rect(&self.conn, self.root, self.layer.get())
+            .map(|rect| (rect.left, rect.top))
+            .unwrap_or((0, 0))
+    }
+
+    /// Whether `event` is a window being created, mapped or renamed.
+    fn is_window_change(&self, event: &Event) -> bool {
+        match event {
+            Event::CreateNotify(_) | Event::MapNotify(_) => true,
+            Event::PropertyNotify(event) => {
+                let atoms = [self.atoms._NET_CLIENT_LIST, self.atoms._NET_WM_NAME, AtomEnum::WM_NAME.into()];
+                atoms.contains(&event.atom)
+            },
+            _ => false,
+        }
+    }
+
+    /// Remembers display changes among events read for some other purpose.
+    fn note_display_change(&self, event: &Event) {
+        let changed = match event {
+            Event::RandrScreenChangeNotify(_) | Event::RandrNotify(_) => true,
+            Event::PropertyNotify(event) => event.window == self.root && event.atom == self.atoms._NET_WORKAREA,
+            _ => false,
+        };
+        if changed {
+            self.displays_changed.set(true);
+        }
+    }
+
+    fn select_events(&self, window: Window, mask: EventMask) {
+        let _ = self.conn.change_window_attributes(window, &ChangeWindowAttributesAux::new().event_mask(mask));
+    }
+
+    /// Waits until the server has processed all requests sent so far.
+    fn sync(&self) -> Result<(), WallpaperError> {
+        self.conn.get_input_focus().map_err(request_failed("GetInputFocus"))?
+            .reply().map_err(request_failed("GetInputFocus"))?;
+        Ok(())
+    }
+
+}
+
+impl DesktopBackend for X11Backend {
+
+    /// Desktop windows and panels of common desktop environments, and layers of other instances.
+    const FORBIDDEN_CLASSES: &'static [&'static str] = &[
+        "WallpaperLayer", "Xfdesktop", "Xfce4-panel", "plasmashell", "Nemo-desktop", "Mate-panel", "Gnome-panel",
+        "Polybar", "Tint2",
+    ];
+
+    type SavedWindow = SavedWindow;
+
+    fn list_windows(&self) -> Result<Vec<WindowId>, WallpaperError> {
+        Ok(self.client_list().into_iter()
+            .filter(|&window| self.should_list(window))
+            .map(to_window_id)
+            .collect())
+    }
+
+    fn list_attached(&self) -> Result<Vec<WindowId>, WallpaperError> {
+        let tree = self.conn.query_tree(self.layer.get()).map_err(request_failed("QueryTree"))?
+            .reply().map_err(request_failed("QueryTree"))?;
+        Ok(tree.children.into_iter().map(to_window_id).collect())
+    }
+
+    fn window_exists(&self, window: WindowId) -> bool {
+        get_parent(&self.conn, to_window(window)).is_some()
+    }
+
+    fn window_title(&self, window: WindowId) -> String {
+        get_property_string(&self.conn, to_window(window), self.atoms._NET_WM_NAME, self.atoms.UTF8_STRING)
+            .or_else(|| get_property_string(&self.conn, to_window(window), AtomEnum::WM_NAME.into(), AtomEnum::STRING.into()))
+            .unwrap_or_default()
+    }
+
+    fn window_class(&self, window: WindowId) -> String {
+        match WmClass::get(&self.conn, to_window(window)).ok().and_then(|cookie| cookie.reply().ok()) {
+            Some(Some(class)) => String::from_utf8_lossy(class.class()).into_owned(),
+            _ => String::new(),
+        }
+    }
+
+    fn window_pid(&self, window: WindowId) -> u32 {
+        let pid = get_property32(&self.conn, to_window(window), self.atoms._NET_WM_PID, AtomEnum::CARDINAL.into());
+        pid.first().cloned().unwrap_or(0)
+    }
+
+    fn process_path(&self, pid: u32) -> Option<PathBuf> {
+        proc_executable(pid)
+    }
+
+    fn process_parents(&self) -> HashMap<u32, u32> {
+        proc_parents()
+    }
+
+    fn window_rect(&self, window: WindowId) -> Result<Rect, WallpaperError> {
+        get_window_rect(&self.conn, self.root, to_window(window)).ok_or(WallpaperError::WindowNotFound)
+    }
+
+    fn is_layer_valid(&self) -> bool {
+        get_parent(&self.conn, self.layer.get()).is_some()
+    }
+
+    fn reacquire_layer(&self) -> Result<(), WallpaperError> {
+        let layer = find_or_spawn_layer(&self.conn, self.root, &self.atoms).ok_or(WallpaperError::UnableToSpawnWorker)?;
+        self.layer.set(layer);
+        Ok(())
+    }
+
+    fn is_wallpaper_layer(&self, window: WindowId) -> bool {
+        to_window(window) == self.layer.get()
+    }
+
+    fn is_attached(&self, window: WindowId) -> bool {
+        get_parent(&self.conn, to_window(window)) == Some(self.layer.get())
+    }
+
+    fn attach(&self, window: WindowId) -> Result<SavedWindow, WallpaperError> {
+        let rect = self.window_rect(window)?;
+        let window = to_window(window);
+        let position = geometry::screen_to_layer(rect, self.layer_origin());
+
+        let attributes = self.conn.get_window_attributes(window).map_err(request_failed("GetWindowAttributes"))?
+            .reply().map_err(request_failed("GetWindowAttributes"))?;
+        let saved = SavedWindow { rect, mapped: attributes.map_state == MapState::VIEWABLE };
+
+        self.conn.reparent_window(window, self.layer.get(), position.left as i16, position.top as i16)
+            .map_err(request_failed("ReparentWindow"))?
+            .check().map_err(|error| WallpaperError::ReparentFailed { os_code: os_code(error) })?;
+        self.conn.map_window(window).map_err(request_failed("MapWindow"))?;
+
+        self.sync()?;
+        Ok(saved)
+    }
+
+    fn detach(&self, window: WindowId, saved: Option<SavedWindow>) -> Result<(), WallpaperError> {
+        let saved = match saved {
+            Some(saved) => saved,
+            None => SavedWindow { rect: self.window_rect(window)?, mapped: true },
+        };
+        let window = to_window(window);
+
+        // mapping a top-level window again lets the window manager pick it up and decorate it
+        self.conn.unmap_window(window).map_err(request_failed("UnmapWindow"))?;
+        self.conn.reparent_window(window, self.root, saved.rect.left as i16, saved.rect.top as i16)
+            .map_err(request_failed("ReparentWindow"))?
+            .check().map_err(|error| WallpaperError::ReparentFailed { os_code: os_code(error) })?;
+
+        let aux = ConfigureWindowAux::new()
+            .width((saved.rect.right - saved.rect.left).max(1) as u32)
+            .height((saved.rect.bottom - saved.rect.top).max(1) as u32);
+        self.conn.configure_window(window, &aux).map_err(request_failed("ConfigureWindow"))?;
+        if saved.mapped {
+            self.conn.map_window(window).map_err(request_failed("MapWindow"))?;
+        }
+
+        self.sync()
+    }
+
+    fn close_window(&self, window: WindowId) {
+        let window = to_window(window);
+
+        let protocols = get_property32(&self.conn, window, self.atoms.WM_PROTOCOLS, AtomEnum::ATOM.into());
+        if protocols.contains(&self.atoms.WM_DELETE_WINDOW) {
+            let event = ClientMessageEvent::new(
+                32, window, self.atoms.WM_PROTOCOLS, [self.atoms.WM_DELETE_WINDOW, CURRENT_TIME, 0, 0, 0],
+            );
+            let _ = self.conn.send_event(false, window, EventMask::NO_EVENT, event);
+        } else {
+            let _ = self.conn.kill_client(window);
+        }
+        let _ = self.conn.flush();
+    }
+
+    fn move_window(&self, window: WindowId, rect: Rect) -> Result<(), WallpaperError> {
+        let aux = ConfigureWindowAux::new()
+            .x(rect.left)
+            .y(rect.top)
+            .width((rect.right - rect.left).max(1) as u32)
+            .height((rect.bottom - rect.top).max(1) as u32);
+
+        self.conn.configure_window(to_window(window), &aux).map_err(request_failed("ConfigureWindow"))?;
+        self.sync()
+    }
+
+    fn restack(&self, windows: &[WindowId]) -> Result<(), WallpaperError> {
+        // raising each window in turn leaves the last one on top
+        let aux = ConfigureWindowAux::new().stack_mode(StackMode::ABOVE);
+        for &window in windows {
+            self.conn.configure_window(to_window(window), &aux).map_err(request_failed("ConfigureWindow"))?;
+        }
+        self.sync()
+    }
+
+    fn map_to_layer(&self, rect: Rect) -> Rect {
+        geometry::screen_to_layer(rect, self.layer_origin())
+    }
+
+    fn monitors(&self) -> Result<Vec<Monitor>, WallpaperError> {
+        let work_area = get_property32(&self.conn, self.root, self.atoms._NET_WORKAREA, AtomEnum::CARDINAL.into());
+        let work_area = match work_area.as_slice() {
+            [x, y, width, height, ..] => Some(Rect {
+                left: *x as i32,
+                top: *y as i32,
+                right: (*x + *width) as i32,
+                bottom: (*y + *height) as i32,
+            }),
+            _ => None,
+        };
+        let fallback_dpi = get_xft_dpi(&self.conn, self.root).unwrap_or(DEFAULT_DPI);
+
+        let monitors = self.conn.randr_get_monitors(self.root, true).ok()
+            .and_then(|cookie| cookie.reply().ok())
+            .map(|reply| reply.monitors)
+            .unwrap_or_default();
+
+        let mut monitors: Vec<Monitor> = monitors.into_iter().map(|monitor| {
+            let rect = Rect {
+                left: monitor.x as i32,
+                top: monitor.y as i32,
+                right: monitor.x as i32 + monitor.width as i32,
+                bottom: monitor.y as i32 + monitor.height as i32,
+            };
+            // _NET_WORKAREA spans all monitors, so clip it to this one
+            let work_area = work_area.map_or(rect, |area| Rect {
+                left: rect.left.max(area.left),
+                top: rect.top.max(area.top),
+                right: rect.right.min(area.right),
+                bottom: rect.bottom.min(area.bottom),
+            });
+            let name = self.conn.get_atom_name(monitor.name).ok()
+                .and_then(|cookie| cookie.reply().ok())
+                .map(|reply| String::from_utf8_lossy(&reply.name).into_owned())
+                .unwrap_or_default();
+            let dpi = geometry::dpi_from_size(
+                (monitor.width.into(), monitor.height.into()),
+                (monitor.width_in_millimeters, monitor.height_in_millimeters),
+            ).unwrap_or(fallback_dpi);
+            Monitor { name, primary: monitor.primary, rect, work_area, dpi }
+        }).collect();
+
+        if monitors.is_empty() {
+            // no RandR 1.5, treat the whole screen as a single monitor
+            let rect = get_window_rect(&self.conn, self.root, self.root).ok_or(WallpaperError::MonitorNotFound)?;
+            let name = "screen".to_owned();
+            let work_area = work_area.unwrap_or(rect);
+            monitors.push(Monitor { name, primary: true, rect, work_area, dpi: fallback_dpi });
+        }
+
+        Ok(monitors)
+    }
+
+    fn displays_changed(&self) -> bool {
+        while let Ok(Some(event)) = self.conn.poll_for_event() {
+            self.note_display_change(&event);
+        }
+        self.displays_changed.replace(false)
+    }
+
+    fn resize_layer(&self) -> Result<(), WallpaperError> {
+        // the root window starts at (0, 0) and grows or shrinks with the monitors
+        let screen = get_window_rect(&self.conn, self.root, self.root).ok_or(WallpaperError::MonitorNotFound)?;
+        let aux = ConfigureWindowAux::new()
+            .x(0)
+            .y(0)
+            .width(screen.width().max(1) as u32)
+            .height(screen.height().max(1) as u32);
+        self.conn.configure_window(self.layer.get(), &aux).map_err(request_failed("ConfigureWindow"))?;
+        self.sync()
+    }
+
+    fn wait_for_changes(&self, timeout: Duration) -> Duration {
+        let start = Instant::now();
+
+        // window events are selected only while waiting so they don't pile up in the queue in between
+        while let Ok(Some(event)) = self.conn.poll_for_event() {
+            self.note_display_change(&event);
+        }
+        let clients = self.client_list();
+        self.select_events(self.root, ROOT_EVENTS | EventMask::SUBSTRUCTURE_NOTIFY);
+        for &client in &clients {
+            self.select_events(client, EventMask::PROPERTY_CHANGE);
+        }
+        let _ = self.conn.flush();
+
+        while let Some(remaining) = timeout.checked_sub(start.elapsed()) {
+            match self.conn.poll_for_event() {
+                Ok(Some(event)) => {
+                    self.note_display_change(&event);
+                    if self.is_window_change(&event) {
+                        break;
+                    }
+                },
+                Ok(None) => wait_readable(self.conn.stream().as_raw_fd(), remaining),
+                Err(_) => {
+                    std::thread::sleep(remaining);
+                    break;
+                },
+            }
+        }
+
+        self.select_events(self.root, ROOT_EVENTS);
+        for client in clients {
+            self.select_events(client, EventMask::NO_EVENT);
+        }
+        let _ = self.conn.flush();
+
+        start.elapsed()
+    }
+
+    fn redraw(&self) {
+        let _ = self.conn.clear_area(true, self.layer.get(), 0, 0, 0, 0);
+        let _ = self.conn.flush();
+    }
+
+}
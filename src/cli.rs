@@ -0,0 +1,684 @@
+//! Headless interface mirroring the `wp` commands of WeebP, for batch files and scheduled tasks.
+
+use std::fmt;
+use std::io;
+
+use crate::autostart;
+use crate::backend::{DesktopBackend, Rect, WindowId};
+use crate::config::Config;
+use crate::supervisor::{self, Supervisor};
+use crate::wallpaper::{
+    Anchor, Engine, Margins, MonitorSelector, Placement, Scaling, WaitPolicy, WallpaperError,
+    WallpaperProperties, WindowSelector,
+};
+
+pub const USAGE: &str = "\
+usage: wallpaper-rs <command> [options]
+
+commands:
+    add [--run <command>] [<selector>] [--fullscreen] [--monitor <monitor>] [--span]
+        [--scaling <scaling>] [<placement options>] [<wait options>]
+                        start <command> and/or find a window and make it a wallpaper, on
+                        <monitor> (index or name as listed by monitors) if given, or
+                        covering all monitors with --span
+    ls                  list wallpapers from bottom to top
+    del <id> [--keep]   close a wallpaper, or with --keep give it back to the desktop
+    mv <id> <x> <y> <width> <height>
+                        move and resize a wallpaper (screen coordinates)
+    fullscreen <id> [--monitor <monitor>] [--scaling <scaling>]
+                        make a wallpaper cover its monitor, or <monitor>
+    span <id> [--scaling <scaling>]
+                        make a wallpaper cover all monitors
+    raise <id>          stack a wallpaper over the one above it
+    lower <id>          stack a wallpaper under the one below it
+    layer <id> <layer>  move a wallpaper to a layer, 0 being the bottom one as listed by ls
+    monitors            list monitors with their indices and scale
+    id <selector>       print id of the first matching window
+    supervise           set up the wallpapers saved by the UI and start them again whenever
+                        the desktop loses them, e.g. when explorer.exe restarts
+    autostart [on|off]  show or change whether supervise runs at login
+
+selectors (all given selectors have to match, any of them can be negated as --not-<selector>;
+--name-contains, --name-regex and --name-glob ignore case unless --case-sensitive is given):
+    --name <title>              exact window title, case included
+    --name-contains <text>      title contains text
+    --name-regex <pattern>      title matches regular expression
+    --name-glob <pattern>       title matches pattern with * and ? wildcards
+    --class <class>             window class
+    --process <name>            executable name of the owning process, e.g. mpv.exe
+    --exe <path>                full executable path of the owning process
+    --pid <pid>                 id of the owning process
+
+scaling of fullscreen and spanning wallpapers with a different aspect ratio than the monitor:
+    stretch (default), fit (letterbox), fill (crop), center or tile (original size)
+
+placement options of add (pixels, relative to the monitor):
+    --rect <x>,<y>,<width>,<height>
+                                put the window exactly there
+    --size <width>x<height>     resize the window and put it at the anchor
+    --anchor <anchor>           topLeft, top, topRight, left, center, right, bottomLeft, bottom
+                                or bottomRight (center)
+    --margin <margin>           distance of an anchored window from the edges, either one for
+                                all of them or <left>,<top>,<right>,<bottom> (0)
+    --work-area                 lay out the window in the area not covered by taskbars
+
+wait options of add (times in milliseconds):
+    --timeout <ms>              give up after waiting this long (5000)
+    --delay <ms>                wait before the first lookup (0)
+    --interval <ms>             delay between the first two lookups (50)
+    --backoff <factor>          multiply the delay by this after every lookup (1.5)
+    --max-interval <ms>         upper limit of the delay (1000)
+    --stable-title <ms>         accept the window once its title stops changing for this long (0)
+    --any-size                  accept windows with zero width or height
+
+Without a command the UI is started.";
+
+#[derive(Debug)]
+pub enum CliError {
+    Usage(String),
+    Engine(WallpaperError),
+    Io(io::Error),
+    /// The command manages wallpapers, which this process can't see because they don't outlive
+    /// the process which added them.
+    NeedsLongRunningInstance,
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::Usage(message) => write!(f, "{}\n\n{}", message, USAGE),
+            CliError::Engine(error) => write!(f, "{}", error),
+            CliError::Io(error) => write!(f, "{}", error),
+            CliError::NeedsLongRunningInstance => write!(
+                f, "Wallpapers only live as long as the process which added them here, use the UI or supervise",
+            ),
+        }
+    }
+}
+
+impl From<WallpaperError> for CliError {
+    fn from(error: WallpaperError) -> CliError {
+        CliError::Engine(error)
+    }
+}
+
+fn usage<T>(message: &str) -> Result<T, CliError> {
+    Err(CliError::Usage(message.to_owned()))
+}
+
+const SELECTOR_OPTIONS: &[&str] = &[
+    "--name", "--name-contains", "--name-regex", "--name-glob", "--class", "--process", "--exe", "--pid",
+];
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Selector {
+    /// Selector options with their values, e.g. `("--class", "mpv")` or `("--not-class", "mpv")`.
+    pub options: Vec<(String, String)>,
+    /// Applies to `--name-contains`, `--name-regex` and `--name-glob`, `--name` always matches exactly.
+    pub case_sensitive: bool,
+}
+
+impl Selector {
+
+    /// Consumes `option` and its value if it is a selector option.
+    fn parse_option<'a>(
+        &mut self, option: &str, args: &mut impl Iterator<Item = &'a String>
+    ) -> Result<bool, CliError> {
+        if option == "--case-sensitive" {
+            self.case_sensitive = true;
+        } else if SELECTOR_OPTIONS.contains(&option.replacen("--not-", "--", 1).as_str()) {
+            let value = parse_value(option, args.next())?;
+            self.options.push((option.to_owned(), value));
+        } else {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn predicate(&self, option: &str, value: &str) -> Result<WindowSelector, CliError> {
+        let case_sensitive = self.case_sensitive;
+        if let Some(option) = option.strip_prefix("--not-") {
+            let selector = self.predicate(&format!("--{}", option), value)?;
+            return Ok(WindowSelector::Not(Box::new(selector)));
+        }
+        let value = value.to_owned();
+        Ok(match option {
+            "--name" => WindowSelector::WindowTitle(value),
+            "--name-contains" => WindowSelector::TitleContains { text: value, case_sensitive },
+            "--name-regex" => WindowSelector::TitleRegex { pattern: value, case_sensitive },
+            "--name-glob" => WindowSelector::TitleGlob { pattern: value, case_sensitive },
+            "--class" => WindowSelector::ClassName(value),
+            "--process" => WindowSelector::ProcessName(value),
+            "--exe" => WindowSelector::ExecutablePath(value),
+            _ => WindowSelector::ProcessId(parse_number(option, Some(&value))?),
+        })
+    }
+
+    /// Combines all given selector options, every one of them has to match.
+    fn to_window_selector(&self) -> Result<WindowSelector, CliError> {
+        let mut predicates = self.options.iter()
+            .map(|(option, value)| self.predicate(option, value))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(match predicates.len() {
+            0 => WindowSelector::None,
+            1 => predicates.remove(0),
+            _ => WindowSelector::All(predicates),
+        })
+    }
+
+}
+
+/// Placement options of `add`, checked for conflicts once all of them are known.
+#[derive(Debug, Default)]
+struct Layout {
+    rect: Option<Rect>,
+    size: Option<(i32, i32)>,
+    anchor: Option<Anchor>,
+    margins: Option<Margins>,
+    work_area: bool,
+}
+
+impl Layout {
+
+    /// Consumes `option` and its value if it is a placement option.
+    fn parse_option<'a>(
+        &mut self, option: &str, args: &mut impl Iterator<Item = &'a String>
+    ) -> Result<bool, CliError> {
+        match option {
+            "--rect" => {
+                let numbers = parse_numbers(option, args.next(), ',', 4)?;
+                let (left, top) = (numbers[0], numbers[1]);
+                self.rect = Some(Rect { left, top, right: left + numbers[2], bottom: top + numbers[3] });
+            },
+            "--size" => {
+                let numbers = parse_numbers(option, args.next(), 'x', 2)?;
+                self.size = Some((numbers[0], numbers[1]));
+            },
+            "--anchor" => {
+                let value = parse_value(option, args.next())?;
+                match value.parse() {
+                    Ok(anchor) => self.anchor = Some(anchor),
+                    Err(_) => return usage(&format!("Unknown anchor '{}'", value)),
+                }
+            },
+            "--margin" => {
+                let value = args.next();
+                self.margins = Some(match parse_numbers(option, value, ',', 1) {
+                    Ok(numbers) => Margins::uniform(numbers[0]),
+                    Err(_) => {
+                        let numbers = parse_numbers(option, value, ',', 4)?;
+                        Margins { left: numbers[0], top: numbers[1], right: numbers[2], bottom: numbers[3] }
+                    },
+                });
+            },
+            "--work-area" => self.work_area = true,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn to_placement(&self) -> Result<Option<Placement>, CliError> {
+        match (self.rect, self.size) {
+            (Some(_), Some(_)) => usage("--rect and --size can't be combined"),
+            (_, None) if self.anchor.is_some() || self.margins.is_some() => usage("--anchor and --margin need --size"),
+            (Some(rect), None) => Ok(Some(Placement::Rect(rect))),
+            (None, Some((width, height))) => Ok(Some(Placement::Anchored {
+                width,
+                height,
+                anchor: self.anchor.unwrap_or(Anchor::Center),
+                margins: self.margins.unwrap_or_default(),
+            })),
+            (None, None) => Ok(None),
+        }
+    }
+
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Add {
+        run: Option<String>,
+        selector: Selector,
+        properties: WallpaperProperties,
+        wait: WaitPolicy,
+    },
+    List,
+    Delete { id: WindowId, keep: bool },
+    Move { id: WindowId, rect: Rect },
+    Fullscreen { id: WindowId, monitor: Option<MonitorSelector>, scaling: Scaling },
+    Span { id: WindowId, scaling: Scaling },
+    Raise { id: WindowId },
+    Lower { id: WindowId },
+    Layer { id: WindowId, layer: usize },
+    Monitors,
+    Id { selector: Selector },
+    Supervise,
+    /// Enables or disables starting at login, or only shows whether it is enabled.
+    Autostart { enabled: Option<bool> },
+    Help,
+}
+
+impl Action {
+
+    /// Whether the action adds, finds or changes wallpapers or windows, as opposed to only
+    /// running the supervisor or looking at the system.
+    fn manages_wallpapers(&self) -> bool {
+        !matches!(self, Action::Monitors | Action::Supervise | Action::Autostart { .. } | Action::Help)
+    }
+
+    /// Whether the action needs the desktop, help and autostart work without a display.
+    pub fn needs_engine(&self) -> bool {
+        !matches!(self, Action::Autostart { .. } | Action::Help)
+    }
+
+}
+
+/// Parses a window id as printed by `ls` and `id`; decimal ids are accepted as well.
+fn parse_id(arg: Option<&String>) -> Result<WindowId, CliError> {
+    let arg = match arg {
+        Some(arg) => arg,
+        None => return usage("Missing window id"),
+    };
+    let parsed = match arg.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => arg.parse(),
+    };
+    parsed.map(WindowId).or_else(|_| usage(&format!("Invalid window id '{}'", arg)))
+}
+
+fn parse_number<T: std::str::FromStr>(option: &str, arg: Option<&String>) -> Result<T, CliError> {
+    match arg.map(|arg| arg.parse()) {
+        Some(Ok(value)) => Ok(value),
+        _ => usage(&format!("{} expects a number", option)),
+    }
+}
+
+/// Parses `count` numbers separated by `separator`, e.g. `1280x720`.
+fn parse_numbers(option: &str, arg: Option<&String>, separator: char, count: usize) -> Result<Vec<i32>, CliError> {
+    let numbers = arg.map(|arg| arg.split(separator).map(str::parse).collect::<Result<Vec<i32>, _>>());
+    match numbers {
+        Some(Ok(numbers)) if numbers.len() == count => Ok(numbers),
+        _ => usage(&format!("{} expects {} numbers separated by '{}'", option, count, separator)),
+    }
+}
+
+fn parse_scaling(arg: Option<&String>) -> Result<Scaling, CliError> {
+    let value = parse_value("--scaling", arg)?;
+    value.parse().or_else(|_| usage(&format!("Unknown scaling '{}'", value)))
+}
+
+fn parse_value(option: &str, arg: Option<&String>) -> Result<String, CliError> {
+    match arg {
+        Some(value) => Ok(value.clone()),
+        None => usage(&format!("{} expects a value", option)),
+    }
+}
+
+pub fn parse(args: &[String]) -> Result<Action, CliError> {
+    let command = match args.first() {
+        Some(command) => command.as_str(),
+        None => return Ok(Action::Help),
+    };
+    let mut args = args[1..].iter();
+
+    let action = match command {
+        "add" => {
+            let mut run = None;
+            let mut selector = Selector::default();
+            let mut properties = WallpaperProperties::default();
+            let mut wait = WaitPolicy::default();
+            let mut layout = Layout::default();
+            while let Some(option) = args.next() {
+                if selector.parse_option(option, &mut args)? || layout.parse_option(option, &mut args)? {
+                    continue;
+                }
+                match option.as_str() {
+                    "--run" => match parse_value(option, args.next())? {
+                        value if value.trim().is_empty() => return usage("--run expects a command"),
+                        value => run = Some(value),
+                    },
+                    "--fullscreen" => properties.fullscreen = true,
+                    "--monitor" => properties.monitor = Some(parse_value(option, args.next())?.as_str().into()),
+                    "--span" => properties.span = true,
+                    "--scaling" => properties.scaling = parse_scaling(args.next())?,
+                    "--timeout" => wait.timeout_ms = parse_number(option, args.next())?,
+                    "--delay" => wait.initial_delay_ms = parse_number(option, args.next())?,
+                    "--interval" => wait.interval_ms = parse_number(option, args.next())?,
+                    "--backoff" => wait.backoff = parse_number(option, args.next())?,
+                    "--max-interval" => wait.max_interval_ms = parse_number(option, args.next())?,
+                    "--stable-title" => wait.stable_title_ms = parse_number(option, args.next())?,
+                    "--any-size" => wait.require_size = false,
+                    _ => return usage(&format!("Unknown option '{}'", option)),
+                }
+            }
+            properties.placement = layout.to_placement()?;
+            properties.work_area = layout.work_area;
+            Action::Add { run, selector, properties, wait }
+        },
+        "ls" => Action::List,
+        "del" => {
+            let id = parse_id(args.next())?;
+            let keep = match args.next().map(String::as_str) {
+                Some("--keep") => true,
+                Some(option) => return usage(&format!("Unknown option '{}'", option)),
+                None => false,
+            };
+            Action::Delete { id, keep }
+        },
+        "mv" => {
+            let id = parse_id(args.next())?;
+            let left = parse_number("x", args.next())?;
+            let top = parse_number("y", args.next())?;
+            let width: i32 = parse_number("width", args.next())?;
+            let height: i32 = parse_number("height", args.next())?;
+            Action::Move { id, rect: Rect { left, top, right: left + width, bottom: top + height } }
+        },
+        "fullscreen" => {
+            let id = parse_id(args.next())?;
+            let (mut monitor, mut scaling) = (None, Scaling::default());
+            while let Some(option) = args.next() {
+                match option.as_str() {
+                    "--monitor" => monitor = Some(parse_value(option, args.next())?.as_str().into()),
+                    "--scaling" => scaling = parse_scaling(args.next())?,
+                    _ => return usage(&format!("Unknown option '{}'", option)),
+                }
+            }
+            Action::Fullscreen { id, monitor, scaling }
+        },
+        "span" => {
+            let id = parse_id(args.next())?;
+            let scaling = match args.next().map(String::as_str) {
+                Some("--scaling") => parse_scaling(args.next())?,
+                Some(option) => return usage(&format!("Unknown option '{}'", option)),
+                None => Scaling::default(),
+            };
+            Action::Span { id, scaling }
+        },
+        "raise" => Action::Raise { id: parse_id(args.next())? },
+        "lower" => Action::Lower { id: parse_id(args.next())? },
+        "layer" => {
+            let id = parse_id(args.next())?;
+            Action::Layer { id, layer: parse_number("layer", args.next())? }
+        },
+        "monitors" => Action::Monitors,
+        "id" => {
+            let mut selector = Selector::default();
+            while let Some(option) = args.next() {
+                if !selector.parse_option(option, &mut args)? {
+                    return usage(&format!("Unknown option '{}'", option));
+                }
+            }
+            Action::Id { selector }
+        },
+        "supervise" => Action::Supervise,
+        "autostart" => {
+            let enabled = match args.next().map(String::as_str) {
+                Some("on") => Some(true),
+                Some("off") => Some(false),
+                Some(arg) => return usage(&format!("autostart expects 'on' or 'off', not '{}'", arg)),
+                None => None,
+            };
+            Action::Autostart { enabled }
+        },
+        "help" | "--help" | "-h" => Action::Help,
+        _ => return usage(&format!("Unknown command '{}'", command)),
+    };
+
+    match args.next() {
+        Some(arg) => usage(&format!("Unexpected argument '{}'", arg)),
+        None => Ok(action),
+    }
+}
+
+fn print_window<B: DesktopBackend>(backend: &B, window: WindowId) {
+    println!("{:#x} {}", window.0, backend.window_title(window));
+}
+
+pub fn execute<B: DesktopBackend>(engine: &Engine<B>, action: Action) -> Result<(), CliError> {
+    if !B::WALLPAPERS_OUTLIVE_PROCESS && action.manages_wallpapers() {
+        return Err(CliError::NeedsLongRunningInstance);
+    }
+    match action {
+        Action::Add { run, selector, properties, wait } => {
+            let mut command = match run {
+                Some(run) => match crate::command_from_str(&run) {
+                    Ok(command) => Some(command),
+                    Err(_) => return usage("Mismatched quotes in --run"),
+                },
+                None => None,
+            };
+            let window = engine.add_window(
+                command.as_mut(), selector.to_window_selector()?, properties, &wait,
+            )?;
+            print_window(engine.backend(), window);
+        },
+        Action::List => {
+            for window in engine.list_active()? {
+                print_window(engine.backend(), window);
+            }
+        },
+        Action::Delete { id, keep: true } => engine.detach_wallpaper(id)?,
+        Action::Delete { id, keep: false } => engine.remove_wallpaper(id)?,
+        Action::Move { id, rect } => engine.move_wallpaper(id, rect)?,
+        Action::Fullscreen { id, monitor, scaling } => engine.make_fullscreen(id, monitor.as_ref(), scaling)?,
+        Action::Span { id, scaling } => engine.span_monitors(id, scaling)?,
+        Action::Raise { id } => engine.raise(id)?,
+        Action::Lower { id } => engine.lower(id)?,
+        Action::Layer { id, layer } => engine.set_layer(id, layer)?,
+        Action::Monitors => {
+            for (index, monitor) in engine.list_monitors()?.into_iter().enumerate() {
+                let rect = monitor.rect;
+                println!(
+                    "{} {} {}x{}{:+}{:+} {}%{}", index, monitor.name, rect.right - rect.left, rect.bottom - rect.top,
+                    rect.left, rect.top, (monitor.scale_factor() * 100.0).round(),
+                    if monitor.primary { " primary" } else { "" },
+                );
+            }
+        },
+        Action::Id { selector } => {
+            let selector = selector.to_window_selector()?;
+            if let WindowSelector::None = selector {
+                return Err(WallpaperError::NothingToSelect.into());
+            }
+            let window = engine.find_window(&selector)?.ok_or(WallpaperError::WindowNotFound)?;
+            print_window(engine.backend(), window);
+        },
+        Action::Supervise => supervise(engine),
+        action @ (Action::Autostart { .. } | Action::Help) => execute_standalone(action)?,
+    }
+    Ok(())
+}
+
+/// Runs an action which doesn't need the desktop, see `Action::needs_engine`.
+pub fn execute_standalone(action: Action) -> Result<(), CliError> {
+    match action {
+        Action::Autostart { enabled } => {
+            if let Some(enabled) = enabled {
+                autostart::set_enabled(enabled).map_err(CliError::Io)?;
+            }
+            println!("{}", if autostart::is_enabled() { "on" } else { "off" });
+        },
+        Action::Help => println!("{}", USAGE),
+        action => unreachable!("{:?} needs the desktop", action),
+    }
+    Ok(())
+}
+
+/// Runs until killed, restarting wallpapers of the config whenever the desktop loses them.
+fn supervise<B: DesktopBackend>(engine: &Engine<B>) -> ! {
+    let mut supervisor = Supervisor::load(Config::default_path());
+    supervisor.apply_all(engine);
+
+    loop {
+        std::thread::sleep(supervisor::CHECK_INTERVAL);
+        match supervisor.check(engine) {
+            Ok(None) => {},
+            Ok(Some(recovery)) => {
+                for window in recovery.restarted {
+                    print_window(engine.backend(), window);
+                }
+                for (entry, error) in recovery.failed {
+                    eprintln!("Failed to restart wallpaper {:?}: {}", entry.command, error);
+                }
+            },
+            Err(error) => eprintln!("Failed to recover wallpaper layer: {}", error),
+        }
+    }
+}
+
+/// Lets a GUI-subsystem executable print to the console it was started from.
+#[cfg(windows)]
+pub fn attach_console() {
+    use winapi::um::wincon::{AttachConsole, ATTACH_PARENT_PROCESS};
+    unsafe { AttachConsole(ATTACH_PARENT_PROCESS) };
+}
+
+#[cfg(not(windows))]
+pub fn attach_console() {}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::{Rect, WindowId};
+    use crate::backend::fake::{FakeDesktop, FakeWindow};
+    use crate::wallpaper::{
+        Anchor, Engine, Margins, MonitorSelector, Placement, Scaling, WaitPolicy, WallpaperProperties,
+    };
+    use super::{execute, parse, Action, CliError, Selector};
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn parses_add() {
+        let action = parse(&args("add --run mpv --class mpv --fullscreen --timeout 10000 --any-size")).unwrap();
+        assert_eq!(action, Action::Add {
+            run: Some("mpv".to_owned()),
+            selector: Selector { options: vec![("--class".to_owned(), "mpv".to_owned())], case_sensitive: false },
+            properties: WallpaperProperties { fullscreen: true, ..WallpaperProperties::default() },
+            wait: WaitPolicy { timeout_ms: 10000, require_size: false, ..WaitPolicy::default() },
+        });
+    }
+
+    #[test]
+    fn parses_placement_options() {
+        let placement = |line: &str| match parse(&args(line)).unwrap() {
+            Action::Add { properties, .. } => (properties.placement, properties.work_area),
+            action => panic!("Unexpected action: {:?}", action),
+        };
+
+        assert_eq!(
+            placement("add --class Clock --rect 20,30,400,200"),
+            (Some(Placement::Rect(Rect { left: 20, top: 30, right: 420, bottom: 230 })), false),
+        );
+        assert_eq!(
+            placement("add --class Clock --size 300x120 --anchor bottomRight --margin 10 --work-area"),
+            (Some(Placement::Anchored {
+                width: 300, height: 120, anchor: Anchor::BottomRight, margins: Margins::uniform(10),
+            }), true),
+        );
+        assert_eq!(
+            placement("add --class Clock --size 300x120 --margin 0,40,0,0"),
+            (Some(Placement::Anchored {
+                width: 300, height: 120, anchor: Anchor::Center,
+                margins: Margins { left: 0, top: 40, right: 0, bottom: 0 },
+            }), false),
+        );
+    }
+
+    #[test]
+    fn parses_monitor_options() {
+        match parse(&args("add --class mpv --monitor HDMI-1 --span --scaling fill")).unwrap() {
+            Action::Add { properties, .. } => {
+                assert_eq!(properties.monitor, Some(MonitorSelector::Name("HDMI-1".to_owned())));
+                assert!(properties.span);
+                assert_eq!(properties.scaling, Scaling::Fill);
+            },
+            action => panic!("Unexpected action: {:?}", action),
+        }
+        assert_eq!(
+            parse(&args("fullscreen 0x1f --scaling fit --monitor 1")).unwrap(),
+            Action::Fullscreen { id: WindowId(31), monitor: Some(MonitorSelector::Index(1)), scaling: Scaling::Fit },
+        );
+        assert_eq!(
+            parse(&args("fullscreen 0x1f")).unwrap(),
+            Action::Fullscreen { id: WindowId(31), monitor: None, scaling: Scaling::Stretch },
+        );
+        assert_eq!(parse(&args("span 0x1f")).unwrap(), Action::Span { id: WindowId(31), scaling: Scaling::Stretch });
+        assert_eq!(
+            parse(&args("span 0x1f --scaling center")).unwrap(),
+            Action::Span { id: WindowId(31), scaling: Scaling::Center },
+        );
+    }
+
+    #[test]
+    fn parses_autostart() {
+        assert_eq!(parse(&args("autostart")).unwrap(), Action::Autostart { enabled: None });
+        assert_eq!(parse(&args("autostart on")).unwrap(), Action::Autostart { enabled: Some(true) });
+        assert_eq!(parse(&args("autostart off")).unwrap(), Action::Autostart { enabled: Some(false) });
+        assert!(parse(&args("autostart maybe")).is_err());
+    }
+
+    #[test]
+    fn parses_window_ids() {
+        assert_eq!(parse(&args("del 0x1f")).unwrap(), Action::Delete { id: WindowId(31), keep: false });
+        assert_eq!(parse(&args("del 31 --keep")).unwrap(), Action::Delete { id: WindowId(31), keep: true });
+        assert_eq!(
+            parse(&args("mv 0x1f -100 0 640 480")).unwrap(),
+            Action::Move { id: WindowId(31), rect: Rect { left: -100, top: 0, right: 540, bottom: 480 } },
+        );
+        assert_eq!(parse(&args("raise 0x1f")).unwrap(), Action::Raise { id: WindowId(31) });
+        assert_eq!(parse(&args("layer 0x1f 2")).unwrap(), Action::Layer { id: WindowId(31), layer: 2 });
+    }
+
+    #[test]
+    fn combines_selector_options() {
+        let engine = Engine::new(FakeDesktop::new());
+        engine.backend().add(FakeWindow::new("DevTools - dashboard", "Chrome_WidgetWin_1", 42));
+        let dashboard = engine.backend().add(FakeWindow::new("Dashboard - Chrome", "Chrome_WidgetWin_1", 42));
+
+        let line = "add --class Chrome_WidgetWin_1 --name-contains dashboard --not-name-contains devtools --timeout 0";
+        execute(&engine, parse(&args(line)).unwrap()).unwrap();
+
+        assert_eq!(engine.list_active().unwrap(), [dashboard]);
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        let lines = [
+            "frobnicate", "add --timeout soon", "add --name", "id --case", "del", "del 0xzz", "fullscreen 1 2",
+            "add --size 300", "add --size 300x120 --anchor middle", "add --anchor center", "add --margin 1,2",
+            "add --rect 0,0,10,10 --size 10x10", "add --scaling squash", "span 1 --scaling", "layer 1", "layer 1 -1",
+        ];
+        for line in &lines {
+            match parse(&args(line)) {
+                Err(CliError::Usage(_)) => {},
+                result => panic!("Unexpected result for '{}': {:?}", line, result),
+            }
+        }
+        for run in &["", "  "] {
+            match parse(&["add".to_owned(), "--run".to_owned(), run.to_string()]) {
+                Err(CliError::Usage(_)) => {},
+                result => panic!("Unexpected result for --run '{}': {:?}", run, result),
+            }
+        }
+    }
+
+    #[test]
+    fn add_and_delete_by_class() {
+        let engine = Engine::new(FakeDesktop::new());
+        let window = engine.backend().add(FakeWindow::new("video.mp4 - mpv", "mpv", 42));
+
+        execute(&engine, parse(&args("add --class mpv --timeout 0")).unwrap()).unwrap();
+        assert_eq!(engine.list_active().unwrap(), [window]);
+
+        match execute(&engine, parse(&args("id --pid forty-two")).unwrap()) {
+            Err(CliError::Usage(_)) => {},
+            result => panic!("Unexpected result: {:?}", result),
+        }
+
+        execute(&engine, Action::Delete { id: window, keep: true }).unwrap();
+        assert!(engine.list_active().unwrap().is_empty());
+        assert!(engine.backend().exists(window));
+    }
+}
@@ -0,0 +1,207 @@
+//! Wallpapers set up through the UI, kept in a JSON file so they come back on the next start.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+
+use crate::backend::{DesktopBackend, WindowId};
+use crate::wallpaper::{Engine, WaitPolicy, WallpaperError, WallpaperProperties, WindowSelector};
+
+/// One wallpaper, either spawned by `command` or picked up by `selector` from running windows.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WallpaperEntry {
+    /// Command line to start, split like a shell would.
+    #[serde(default)]
+    pub command: Option<String>,
+    /// Directory to start `command` in, the current one if not set.
+    #[serde(default)]
+    pub working_dir: Option<PathBuf>,
+    #[serde(default = "no_selector")]
+    pub selector: WindowSelector,
+    pub properties: WallpaperProperties,
+    #[serde(default)]
+    pub wait: WaitPolicy,
+}
+
+fn no_selector() -> WindowSelector {
+    WindowSelector::None
+}
+
+impl WallpaperEntry {
+
+    /// Starts the command if there is one and attaches the selected window.
+    pub fn apply<B: DesktopBackend>(&self, engine: &Engine<B>) -> Result<WindowId, WallpaperError> {
+        let command = self.command.as_ref().filter(|command| !command.trim().is_empty());
+        let mut command = match command {
+            Some(command) => {
+                let mut command = crate::command_from_str(command)?;
+                if let Some(dir) = &self.working_dir {
+                    command.current_dir(dir);
+                }
+                Some(command)
+            },
+            None => None,
+        };
+        engine.add_window(command.as_mut(), self.selector.clone(), self.properties.clone(), &self.wait)
+    }
+
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Config {
+    pub wallpapers: Vec<WallpaperEntry>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(serde_json::Error),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(error) => write!(f, "Can't access config file: {}", error),
+            ConfigError::Parse(error) => write!(f, "Invalid config file: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io(error) => Some(error),
+            ConfigError::Parse(error) => Some(error),
+        }
+    }
+}
+
+/// Per-user configuration directory: `%APPDATA%` on Windows, `$XDG_CONFIG_HOME` (or `~/.config`)
+/// elsewhere. `None` if the variables are not set.
+pub fn config_dir() -> Option<PathBuf> {
+    if cfg!(windows) {
+        std::env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+    }
+}
+
+impl Config {
+
+    /// `wallpaper-rs/config.json` in the `config_dir`.
+    pub fn default_path() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join("wallpaper-rs").join("config.json"))
+    }
+
+    /// Reads the config at `path`; a missing file is an empty config.
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        match fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text).map_err(ConfigError::Parse),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(error) => Err(ConfigError::Io(error)),
+        }
+    }
+
+    /// Writes the config to `path`, creating its directory. The file is replaced at once, so
+    /// a crash while saving can't leave a truncated config behind.
+    pub fn save(&self, path: &Path) -> Result<(), ConfigError> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(ConfigError::Io)?;
+        }
+        let text = serde_json::to_string_pretty(self).map_err(ConfigError::Parse)?;
+        let temporary = path.with_extension("json.tmp");
+        fs::write(&temporary, text).map_err(ConfigError::Io)?;
+        fs::rename(&temporary, path).map_err(ConfigError::Io)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::fake::{FakeDesktop, FakeWindow};
+    use crate::wallpaper::{Engine, WaitPolicy, WallpaperError, WallpaperProperties, WindowSelector};
+    use super::{Config, WallpaperEntry};
+
+    fn entry(command: Option<&str>, selector: WindowSelector) -> WallpaperEntry {
+        WallpaperEntry {
+            command: command.map(str::to_owned),
+            working_dir: None,
+            selector,
+            properties: WallpaperProperties { fullscreen: true, ..WallpaperProperties::default() },
+            wait: WaitPolicy { timeout_ms: 0, ..WaitPolicy::default() },
+        }
+    }
+
+    #[test]
+    fn parses_minimal_entries() {
+        let config: Config = serde_json::from_str(r#"{"wallpapers": [
+            {"command": "mpv --loop video.mp4", "properties": {"fullscreen": true}},
+            {"selector": {"className": "Clock"}, "properties": {"fullscreen": false}, "wait": {"timeoutMs": 100}}
+        ]}"#).unwrap();
+
+        assert_eq!(config.wallpapers[0].command.as_deref(), Some("mpv --loop video.mp4"));
+        assert_eq!(config.wallpapers[0].selector, WindowSelector::None);
+        assert_eq!(config.wallpapers[0].wait, WaitPolicy::default());
+        assert_eq!(config.wallpapers[1].command, None);
+        assert_eq!(config.wallpapers[1].selector, WindowSelector::ClassName("Clock".to_owned()));
+        assert_eq!(config.wallpapers[1].wait.timeout_ms, 100);
+        assert_eq!(config.wallpapers[1].wait.interval_ms, WaitPolicy::default().interval_ms);
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let path = std::env::temp_dir()
+            .join(format!("wallpaper-rs-test-{}", std::process::id()))
+            .join("config.json");
+        let config = Config { wallpapers: vec![
+            entry(Some("mpv \"my video.mp4\""), WindowSelector::TitleRegex {
+                pattern: r"^\w+ - mpv$".to_owned(), case_sensitive: false,
+            }),
+        ] };
+
+        assert_eq!(Config::load(&path).unwrap(), Config::default());
+        config.save(&path).unwrap();
+        assert_eq!(Config::load(&path).unwrap(), config);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn apply_spawns_command() {
+        let engine = Engine::new(FakeDesktop::new());
+        engine.backend().on_spawn(vec![FakeWindow::new("video.mp4 - mpv", "mpv", 0)], 0);
+
+        let window = entry(Some("mpv video.mp4"), WindowSelector::None).apply(&engine).unwrap();
+
+        assert_eq!(engine.backend().spawned(), ["mpv"]);
+        assert_eq!(engine.list_active().unwrap(), [window]);
+    }
+
+    #[test]
+    fn apply_picks_up_running_window() {
+        let engine = Engine::new(FakeDesktop::new());
+        let window = engine.backend().add(FakeWindow::new("clock", "Clock", 42));
+
+        let entry = entry(None, WindowSelector::ClassName("Clock".to_owned()));
+
+        assert_eq!(entry.apply(&engine).unwrap(), window);
+        assert!(engine.backend().spawned().is_empty());
+    }
+
+    #[test]
+    fn apply_rejects_mismatched_quotes() {
+        let engine = Engine::new(FakeDesktop::new());
+
+        match entry(Some("mpv \"video.mp4"), WindowSelector::None).apply(&engine) {
+            Err(WallpaperError::InvalidCommand(_)) => {},
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert!(engine.backend().spawned().is_empty());
+    }
+}
@@ -0,0 +1,473 @@
+//! Rectangle math behind wallpaper placement, free of any platform API so that it can be
+//! tested with arbitrary monitor layouts.
+//!
+//! Everything is in screen coordinates unless noted otherwise. The primary monitor starts at
+//! (0, 0), so monitors left of or above it have negative coordinates. The wallpaper layer is
+//! a window covering all monitors, its own coordinates start at its top-left corner.
+//!
+//! Screen coordinates are physical pixels. Sizes given by users are logical pixels, which are
+//! physical pixels at `DEFAULT_DPI` and get larger on monitors with a higher scale factor.
+
+use serde::{Serialize, Deserialize};
+
+/// DPI of a monitor at 100% scale.
+pub const DEFAULT_DPI: u32 = 96;
+
+/// Converts a length from `from` DPI to `to` DPI, rounded to whole pixels.
+pub fn rescale(length: i32, from: u32, to: u32) -> i32 {
+    (length as f64 * to as f64 / from as f64).round() as i32
+}
+
+/// Converts a `(width, height)` size from `from` DPI to `to` DPI.
+pub fn rescale_size(size: (i32, i32), from: u32, to: u32) -> (i32, i32) {
+    (rescale(size.0, from, to), rescale(size.1, from, to))
+}
+
+/// Effective DPI of a monitor of `pixels` showing `millimeters` physically, rounded to the nearest
+/// 25% scale step the way desktop environments offer them. `None` for sizes no real monitor has,
+/// such as projectors reporting 0 or just their aspect ratio.
+#[cfg(any(test, all(unix, not(target_os = "macos"), not(feature = "wayland"))))]
+pub fn dpi_from_size(pixels: (u32, u32), millimeters: (u32, u32)) -> Option<u32> {
+    // the diagonal doesn't care whether a rotated output reports its sides swapped
+    let diagonal_pixels = (pixels.0 as f64).hypot(pixels.1 as f64);
+    let diagonal_inches = (millimeters.0 as f64).hypot(millimeters.1 as f64) / 25.4;
+    let dpi = diagonal_pixels / diagonal_inches;
+    if !(50.0..=500.0).contains(&dpi) {
+        return None;
+    }
+    let scale = (dpi / DEFAULT_DPI as f64 * 4.0).round().max(4.0) / 4.0;
+    Some((DEFAULT_DPI as f64 * scale).round() as u32)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rect {
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+}
+
+impl Rect {
+
+    pub fn width(&self) -> i32 {
+        self.right - self.left
+    }
+
+    pub fn height(&self) -> i32 {
+        self.bottom - self.top
+    }
+
+    pub fn offset(&self, dx: i32, dy: i32) -> Rect {
+        Rect { left: self.left + dx, top: self.top + dy, right: self.right + dx, bottom: self.bottom + dy }
+    }
+
+    /// Smallest rectangle containing both.
+    pub fn union(&self, other: &Rect) -> Rect {
+        Rect {
+            left: self.left.min(other.left),
+            top: self.top.min(other.top),
+            right: self.right.max(other.right),
+            bottom: self.bottom.max(other.bottom),
+        }
+    }
+
+    /// Converts all coordinates from `from` DPI to `to` DPI, for rectangles relative to a corner.
+    pub fn rescale(&self, from: u32, to: u32) -> Rect {
+        Rect {
+            left: rescale(self.left, from, to),
+            top: rescale(self.top, from, to),
+            right: rescale(self.right, from, to),
+            bottom: rescale(self.bottom, from, to),
+        }
+    }
+
+    /// The rectangle shrunk by `margins` on each side.
+    pub fn inset(&self, margins: Margins) -> Rect {
+        Rect {
+            left: self.left + margins.left,
+            top: self.top + margins.top,
+            right: self.right - margins.right,
+            bottom: self.bottom - margins.bottom,
+        }
+    }
+
+    /// Squared distance from point (`x`, `y`) to the closest pixel of the rectangle, 0 inside it.
+    pub fn distance_squared(&self, x: i32, y: i32) -> i64 {
+        let dx = (self.left - x).max(x - self.right + 1).max(0) as i64;
+        let dy = (self.top - y).max(y - self.bottom + 1).max(0) as i64;
+        dx * dx + dy * dy
+    }
+
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Monitor {
+    /// Name given by the system, e.g. `\\.\DISPLAY1` on Windows or `HDMI-1` on X11.
+    pub name: String,
+    pub primary: bool,
+    /// Full monitor area in screen coordinates.
+    pub rect: Rect,
+    /// Monitor area without taskbars and docked toolbars, in screen coordinates.
+    pub work_area: Rect,
+    /// Effective DPI, `DEFAULT_DPI` at 100% scale and e.g. 144 at 150%.
+    pub dpi: u32,
+}
+
+impl Monitor {
+
+    /// Scale factor set for the monitor, e.g. 1.5 at 150%.
+    pub fn scale_factor(&self) -> f64 {
+        self.dpi as f64 / DEFAULT_DPI as f64
+    }
+
+}
+
+/// Picks the monitor containing point (`x`, `y`), or the one closest to it.
+pub fn nearest_monitor(monitors: &[Monitor], x: i32, y: i32) -> Option<Monitor> {
+    monitors.iter().min_by_key(|monitor| monitor.rect.distance_squared(x, y)).cloned()
+}
+
+/// Bounding box of all monitors, which is also the area of the wallpaper layer.
+pub fn virtual_screen(monitors: &[Monitor]) -> Option<Rect> {
+    monitors.iter().map(|monitor| monitor.rect).reduce(|union, rect| union.union(&rect))
+}
+
+/// Converts `rect` to the coordinates of the wallpaper layer, whose top-left corner is at
+/// `origin` on the screen.
+#[cfg(any(test, windows, all(unix, not(target_os = "macos"), not(feature = "wayland"))))]
+pub fn screen_to_layer(rect: Rect, origin: (i32, i32)) -> Rect {
+    rect.offset(-origin.0, -origin.1)
+}
+
+/// Moves `rect` from monitor `from` to `to`, keeping its size and its offset from the top-left
+/// corner in logical pixels, so that it grows on a monitor with a higher scale factor.
+pub fn move_between(rect: Rect, from: &Monitor, to: &Monitor) -> Rect {
+    rect.offset(-from.rect.left, -from.rect.top).rescale(from.dpi, to.dpi).offset(to.rect.left, to.rect.top)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Anchor {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl Anchor {
+
+    /// Horizontal and vertical position, 0 for left/top, 1 for the middle and 2 for right/bottom.
+    fn position(self) -> (i32, i32) {
+        match self {
+            Anchor::TopLeft => (0, 0),
+            Anchor::Top => (1, 0),
+            Anchor::TopRight => (2, 0),
+            Anchor::Left => (0, 1),
+            Anchor::Center => (1, 1),
+            Anchor::Right => (2, 1),
+            Anchor::BottomLeft => (0, 2),
+            Anchor::Bottom => (1, 2),
+            Anchor::BottomRight => (2, 2),
+        }
+    }
+
+}
+
+impl std::str::FromStr for Anchor {
+    type Err = serde_json::Error;
+
+    /// Parses the names used in the config, e.g. `bottomRight`.
+    fn from_str(text: &str) -> Result<Anchor, serde_json::Error> {
+        serde_json::from_value(serde_json::Value::String(text.to_owned()))
+    }
+}
+
+/// Distances kept from the edges of the monitor or work area by anchored wallpapers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Margins {
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+}
+
+impl Margins {
+
+    pub fn uniform(margin: i32) -> Margins {
+        Margins { left: margin, top: margin, right: margin, bottom: margin }
+    }
+
+    pub fn rescale(&self, from: u32, to: u32) -> Margins {
+        Margins {
+            left: rescale(self.left, from, to),
+            top: rescale(self.top, from, to),
+            right: rescale(self.right, from, to),
+            bottom: rescale(self.bottom, from, to),
+        }
+    }
+
+}
+
+/// A `width`x`height` rectangle put at `anchor` of `area`. It may stick out of `area` when it
+/// is larger.
+pub fn anchored(width: i32, height: i32, anchor: Anchor, area: Rect) -> Rect {
+    let (horizontal, vertical) = anchor.position();
+    let left = area.left + (area.width() - width) * horizontal / 2;
+    let top = area.top + (area.height() - height) * vertical / 2;
+    Rect { left, top, right: left + width, bottom: top + height }
+}
+
+/// How a window covering an area deals with the area having a different aspect ratio.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Scaling {
+    /// Cover the area exactly, distorting the window.
+    #[default]
+    Stretch,
+    /// Scale to the largest size which fits, leaving bars at two sides.
+    Fit,
+    /// Scale to the smallest size which covers the area, cropping two sides. The cropped parts
+    /// show on adjacent monitors, as windows are only clipped to the wallpaper layer.
+    Fill,
+    /// Keep the original size, centered.
+    Center,
+    /// Keep the original size in the top-left corner. A window can't be repeated, so this is
+    /// the first tile of a tiled wallpaper.
+    Tile,
+}
+
+impl std::str::FromStr for Scaling {
+    type Err = serde_json::Error;
+
+    /// Parses the names used in the config, e.g. `fit`.
+    fn from_str(text: &str) -> Result<Scaling, serde_json::Error> {
+        serde_json::from_value(serde_json::Value::String(text.to_owned()))
+    }
+}
+
+impl Scaling {
+
+    /// Rectangle of a window of `width`x`height` covering `area`. Without a size, e.g. for a
+    /// window which isn't laid out yet, the aspect ratio is unknown and `area` is stretched over.
+    pub fn apply(self, width: i32, height: i32, area: Rect) -> Rect {
+        if width <= 0 || height <= 0 {
+            return area;
+        }
+        match self {
+            Scaling::Stretch => area,
+            Scaling::Fit => scale(width, height, area, false),
+            Scaling::Fill => scale(width, height, area, true),
+            Scaling::Center => anchored(width, height, Anchor::Center, area),
+            Scaling::Tile => anchored(width, height, Anchor::TopLeft, area),
+        }
+    }
+
+}
+
+/// Centered rectangle with the aspect ratio of `width`x`height`, as large as fits into `area`,
+/// or as small as covers it when `cover` is set.
+fn scale(width: i32, height: i32, area: Rect, cover: bool) -> Rect {
+    let (width, height) = (width as i64, height as i64);
+    let (area_width, area_height) = (area.width() as i64, area.height() as i64);
+
+    // compares the aspect ratios without dividing; a taller window is limited by height to fit
+    let taller = width * area_height < height * area_width;
+    let (scaled_width, scaled_height) = if taller != cover {
+        ((width * area_height + height / 2) / height, area_height)
+    } else {
+        (area_width, (height * area_width + width / 2) / width)
+    };
+    anchored(scaled_width as i32, scaled_height as i32, Anchor::Center, area)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        anchored, dpi_from_size, move_between, nearest_monitor, rescale, rescale_size, screen_to_layer,
+        virtual_screen, Anchor, Margins, Monitor, Rect, Scaling, DEFAULT_DPI,
+    };
+
+    fn rect(left: i32, top: i32, right: i32, bottom: i32) -> Rect {
+        Rect { left, top, right, bottom }
+    }
+
+    fn monitor(name: &str, rect: Rect, dpi: u32) -> Monitor {
+        Monitor { name: name.to_owned(), primary: rect.left == 0 && rect.top == 0, rect, work_area: rect, dpi }
+    }
+
+    /// 1920x1080 primary, a 1280x1024 monitor left of it and a 2560x1440 one at 150% above it,
+    /// so that the virtual screen starts at negative coordinates on both axes.
+    fn layout() -> Vec<Monitor> {
+        vec![
+            monitor("PRIMARY", rect(0, 0, 1920, 1080), DEFAULT_DPI),
+            monitor("LEFT", rect(-1280, 56, 0, 1080), DEFAULT_DPI),
+            monitor("ABOVE", rect(-320, -1440, 2240, 0), 144),
+        ]
+    }
+
+    #[test]
+    fn rect_dimensions() {
+        let screen = rect(-1280, -20, 0, 1004);
+        assert_eq!((screen.width(), screen.height()), (1280, 1024));
+        assert_eq!((rect(10, 0, 10, 5).width(), rect(0, 10, 5, 0).height()), (0, -10));
+    }
+
+    #[test]
+    fn rect_offset_union_and_inset() {
+        assert_eq!(rect(0, 0, 100, 50).offset(-150, 20), rect(-150, 20, -50, 70));
+        assert_eq!(rect(-1280, 56, 0, 1080).union(&rect(0, 0, 1920, 1080)), rect(-1280, 0, 1920, 1080));
+        let margins = Margins { left: 1, top: 2, right: 3, bottom: 4 };
+        assert_eq!(rect(0, 0, 1920, 1080).inset(margins), rect(1, 2, 1917, 1076));
+    }
+
+    #[test]
+    fn rect_distance() {
+        let screen = rect(-1280, 0, 0, 1024);
+        assert_eq!(screen.distance_squared(-1280, 0), 0);
+        assert_eq!(screen.distance_squared(-1, 1023), 0);
+        assert_eq!(screen.distance_squared(0, 0), 1);
+        assert_eq!(screen.distance_squared(-1283, -4), 9 + 16);
+    }
+
+    #[test]
+    fn nearest_monitor_picks_containing_or_closest() {
+        let monitors = layout();
+        let name = |x, y| nearest_monitor(&monitors, x, y).unwrap().name;
+
+        assert_eq!(name(0, 0), "PRIMARY");
+        assert_eq!(name(-1, 500), "LEFT");
+        assert_eq!(name(-1, -1), "ABOVE");
+        assert_eq!(name(100, -1), "ABOVE");
+        assert_eq!(name(-1200, 20), "LEFT");
+        assert_eq!(name(2500, 1000), "PRIMARY");
+        assert_eq!(nearest_monitor(&[], 0, 0), None);
+    }
+
+    #[test]
+    fn virtual_screen_covers_all_monitors() {
+        assert_eq!(virtual_screen(&layout()), Some(rect(-1280, -1440, 2240, 1080)));
+        assert_eq!(virtual_screen(&layout()[..1]), Some(rect(0, 0, 1920, 1080)));
+        assert_eq!(virtual_screen(&[]), None);
+    }
+
+    #[test]
+    fn layer_mapping_with_negative_origin() {
+        let origin = virtual_screen(&layout()).map(|screen| (screen.left, screen.top)).unwrap();
+
+        // primary monitor is not at the layer's origin once there are monitors left of or above it
+        assert_eq!(screen_to_layer(rect(0, 0, 1920, 1080), origin), rect(1280, 1440, 3200, 2520));
+        assert_eq!(screen_to_layer(rect(-1280, 56, 0, 1080), origin), rect(0, 1496, 1280, 2520));
+        assert_eq!(screen_to_layer(rect(-320, -1440, 2240, 0), origin), rect(960, 0, 3520, 1440));
+        for monitor in layout() {
+            assert_eq!(screen_to_layer(monitor.rect, origin).offset(origin.0, origin.1), monitor.rect);
+        }
+        assert_eq!(screen_to_layer(rect(10, 10, 20, 20), (0, 0)), rect(10, 10, 20, 20));
+    }
+
+    #[test]
+    fn move_between_keeps_offset_from_corner() {
+        let monitors = layout();
+        let (primary, left) = (&monitors[0], &monitors[1]);
+        let window = rect(100, 100, 740, 580);
+        assert_eq!(move_between(window, primary, left), rect(-1180, 156, -540, 636));
+        assert_eq!(move_between(rect(-1180, 156, -540, 636), left, primary), window);
+        assert_eq!(move_between(window, primary, primary), window);
+    }
+
+    #[test]
+    fn move_between_scales_to_monitor_dpi() {
+        let monitors = layout();
+        let (primary, above) = (&monitors[0], &monitors[2]);
+        let window = rect(100, 100, 740, 580);
+        assert_eq!(move_between(window, primary, above), rect(-170, -1290, 790, -570));
+        assert_eq!(move_between(rect(-170, -1290, 790, -570), above, primary), window);
+    }
+
+    #[test]
+    fn rescale_rounds_to_pixels() {
+        assert_eq!(rescale(300, DEFAULT_DPI, 144), 450);
+        assert_eq!(rescale(-101, DEFAULT_DPI, 144), -152);
+        assert_eq!(rescale(450, 144, DEFAULT_DPI), 300);
+        assert_eq!(rescale(125, 120, 168), 175);
+        assert_eq!(rescale_size((1280, 720), 192, DEFAULT_DPI), (640, 360));
+        assert_eq!(rect(20, -20, 420, 220).rescale(DEFAULT_DPI, 192), rect(40, -40, 840, 440));
+        assert_eq!(Margins::uniform(10).rescale(DEFAULT_DPI, 120), Margins::uniform(13));
+        assert_eq!(layout()[2].scale_factor(), 1.5);
+    }
+
+    #[test]
+    fn dpi_from_size_snaps_to_scale_steps() {
+        // 24" 1080p, 27" 1440p and 27" 4K
+        assert_eq!(dpi_from_size((1920, 1080), (527, 296)), Some(DEFAULT_DPI));
+        assert_eq!(dpi_from_size((2560, 1440), (597, 336)), Some(120));
+        assert_eq!(dpi_from_size((3840, 2160), (597, 336)), Some(168));
+        // rotated output reporting its physical size unrotated
+        assert_eq!(dpi_from_size((2160, 3840), (597, 336)), Some(168));
+        // no size or just the aspect ratio
+        assert_eq!(dpi_from_size((1920, 1080), (0, 0)), None);
+        assert_eq!(dpi_from_size((1920, 1080), (16, 9)), None);
+    }
+
+    #[test]
+    fn anchored_covers_every_anchor() {
+        let area = rect(-1280, 0, 0, 1024).inset(Margins::uniform(10));
+        let expected = [
+            (Anchor::TopLeft, (-1270, 10)), (Anchor::Top, (-740, 10)), (Anchor::TopRight, (-210, 10)),
+            (Anchor::Left, (-1270, 462)), (Anchor::Center, (-740, 462)), (Anchor::Right, (-210, 462)),
+            (Anchor::BottomLeft, (-1270, 914)), (Anchor::Bottom, (-740, 914)), (Anchor::BottomRight, (-210, 914)),
+        ];
+        for &(anchor, (left, top)) in &expected {
+            assert_eq!(anchored(200, 100, anchor, area), rect(left, top, left + 200, top + 100), "{:?}", anchor);
+        }
+    }
+
+    #[test]
+    fn fit_letterboxes() {
+        // 4:3 video on a 16:9 monitor left of the primary one: bars left and right
+        assert_eq!(Scaling::Fit.apply(640, 480, rect(-1920, 0, 0, 1080)), rect(-1680, 0, -240, 1080));
+        // 21:9 video on a 16:10 monitor above the primary one: bars at the top and bottom
+        assert_eq!(Scaling::Fit.apply(2560, 1080, rect(0, -1200, 1920, 0)), rect(0, -1005, 1920, -195));
+        // same aspect ratio covers the area exactly
+        assert_eq!(Scaling::Fit.apply(1280, 720, rect(0, 0, 1920, 1080)), rect(0, 0, 1920, 1080));
+    }
+
+    #[test]
+    fn fill_crops() {
+        assert_eq!(Scaling::Fill.apply(640, 480, rect(-1920, 0, 0, 1080)), rect(-1920, -180, 0, 1260));
+        assert_eq!(Scaling::Fill.apply(2560, 1080, rect(0, -1200, 1920, 0)), rect(-462, -1200, 2382, 0));
+        assert_eq!(Scaling::Fill.apply(1280, 720, rect(0, 0, 1920, 1080)), rect(0, 0, 1920, 1080));
+    }
+
+    #[test]
+    fn center_and_tile_keep_size() {
+        let area = rect(-1280, 0, 0, 1024);
+        assert_eq!(Scaling::Center.apply(640, 480, area), rect(-960, 272, -320, 752));
+        assert_eq!(Scaling::Tile.apply(640, 480, area), rect(-1280, 0, -640, 480));
+        // larger windows stick out evenly
+        assert_eq!(Scaling::Center.apply(2000, 1200, rect(0, 0, 1920, 1080)), rect(-40, -60, 1960, 1140));
+    }
+
+    #[test]
+    fn stretch_and_unknown_sizes_cover_area() {
+        let area = rect(-1280, 0, 0, 1024);
+        assert_eq!(Scaling::Stretch.apply(640, 480, area), area);
+        for &scaling in &[Scaling::Fit, Scaling::Fill, Scaling::Center, Scaling::Tile] {
+            assert_eq!(scaling.apply(0, 480, area), area);
+            assert_eq!(scaling.apply(640, 0, area), area);
+        }
+    }
+
+    #[test]
+    fn anchor_parses_config_names() {
+        assert_eq!("bottomLeft".parse::<Anchor>().unwrap(), Anchor::BottomLeft);
+        assert!("bottom-left".parse::<Anchor>().is_err());
+        assert_eq!("fill".parse::<Scaling>().unwrap(), Scaling::Fill);
+    }
+}
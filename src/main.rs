@@ -1,28 +1,35 @@
-#![feature(drain_filter)]
-
 #![windows_subsystem = "windows"]
 
+use std::path::PathBuf;
+
 use serde::{Serialize, Deserialize};
 use web_view::*;
-use winapi::shared::windef::HWND;
 
+use backend::{DesktopBackend, WindowId};
+use config::{Config, WallpaperEntry};
+use supervisor::Supervisor;
+
+mod autostart;
+mod backend;
+mod cli;
+mod config;
+mod geometry;
+mod selector;
 mod shellwords;
+mod supervisor;
 mod wallpaper;
 
-#[derive(Debug, Serialize, Deserialize)]
-struct UserData { }
-
 #[derive(Debug, Serialize, Deserialize)]
 struct Window {
-    hwnd: u32,
+    hwnd: u64,
     title: String,
 }
 
 impl Window {
 
-    fn from_handles(handles: Vec<HWND>) -> Vec<Window> {
+    fn from_handles<B: DesktopBackend>(backend: &B, handles: Vec<WindowId>) -> Vec<Window> {
         handles.into_iter().map(|hwnd| {
-            Window { title: wallpaper::get_window_name(hwnd), hwnd: hwnd as u32 }
+            Window { title: backend.window_title(hwnd), hwnd: hwnd.0 }
         }).collect()
     }
 
@@ -30,71 +37,212 @@ impl Window {
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
+#[allow(clippy::enum_variant_names)]
 enum Command<'a> {
     UpdateRunningWallpapers {},
     UpdateActiveWindows {},
+    UpdateMonitors {},
     NewFromSelectedActiveWindow { 
-        selected: u32,
+        selected: u64,
         properties: wallpaper::WallpaperProperties,
     },
     NewFromCustomCommand { 
         command: &'a str, 
-        selector: wallpaper::WindowSelector<'a>, 
+        #[serde(default)]
+        working_dir: Option<PathBuf>,
+        selector: wallpaper::WindowSelector, 
         properties: wallpaper::WallpaperProperties,
+        #[serde(default)]
+        wait: wallpaper::WaitPolicy,
     },
-    TerminateRunningWallpaper { selected: u32 },
+    DetachRunningWallpaper { selected: u64 },
+    TerminateRunningWallpaper { selected: u64 },
+    RaiseRunningWallpaper { selected: u64 },
+    LowerRunningWallpaper { selected: u64 },
+    SetRunningWallpaperLayer { selected: u64, layer: usize },
+    SuperviseWallpapers {},
+    GetAutostart {},
+    SetAutostart { enabled: bool },
 }
 
-fn command_from_str(command: &str) -> Result<std::process::Command, shellwords::MismatchedQuotes> {
-    let mut iter = shellwords::split(command)?.into_iter();
-    let mut command = std::process::Command::new(iter.next().unwrap());
+/// Splits `command` into a program and its arguments, failing on mismatched quotes or a blank command.
+fn command_from_str(command: &str) -> Result<std::process::Command, wallpaper::WallpaperError> {
+    let invalid = || wallpaper::WallpaperError::InvalidCommand(command.to_owned());
+    let mut iter = shellwords::split(command).map_err(|_| invalid())?.into_iter();
+    let mut command = std::process::Command::new(iter.next().ok_or_else(invalid)?);
     for word in iter {
         command.arg(word);
     }
     Ok(command)
 }
 
-fn handler(web_view: &mut WebView<UserData>, arg: &str) -> WVResult {
-    let wp = wallpaper::Engine::new().expect("Failed to create wallpaper engine");
-    let arg: Command = serde_json::from_str(arg).unwrap();
+fn show_error(web_view: &mut WebView<Supervisor>, message: &str) -> WVResult {
+    eprintln!("{}", message);
+    let message_stringified = serde_json::to_string(message).unwrap();
+    web_view.eval(&format!("window._showError({})", message_stringified))
+}
+
+fn handler<B: DesktopBackend>(
+    wp: &wallpaper::Engine<B>, web_view: &mut WebView<Supervisor>, arg: &str
+) -> WVResult {
+    let arg: Command = match serde_json::from_str(arg) {
+        Ok(command) => command,
+        Err(error) => return show_error(web_view, &format!("Invalid request: {}", error)),
+    };
+    let mut saved = Ok(());
     
-    match arg {
+    let result = match arg {
         Command::UpdateActiveWindows {} => {
-            let windows = Window::from_handles(wallpaper::list_windows());
-            let windows_stringified = serde_json::to_string(&windows).unwrap();
-            web_view.eval(&format!("window._updateList('activeWindows', {})", windows_stringified)).unwrap();
+            wp.list_windows().map(|handles| {
+                let windows = Window::from_handles(wp.backend(), handles);
+                let windows_stringified = serde_json::to_string(&windows).unwrap();
+                web_view.eval(&format!("window._updateList('activeWindows', {})", windows_stringified)).unwrap();
+            })
         },
         Command::UpdateRunningWallpapers {} => {
-            let windows = Window::from_handles(wp.list_active());
-            let windows_stringified = serde_json::to_string(&windows).unwrap();
-            web_view.eval(&format!("window._updateList('runningWallpapers', {})", windows_stringified)).unwrap();
+            wp.list_active().map(|handles| {
+                // topmost first, the way layers are usually listed
+                let windows = Window::from_handles(wp.backend(), handles.into_iter().rev().collect());
+                let windows_stringified = serde_json::to_string(&windows).unwrap();
+                web_view.eval(&format!("window._updateList('runningWallpapers', {})", windows_stringified)).unwrap();
+            })
+        },
+        Command::UpdateMonitors {} => {
+            wp.list_monitors().map(|monitors| {
+                let monitors_stringified = serde_json::to_string(&monitors).unwrap();
+                web_view.eval(&format!("window._updateMonitors({})", monitors_stringified)).unwrap();
+            })
         },
         Command::NewFromSelectedActiveWindow { selected, properties } => {
-            let result = wp.add_window_by_handle(selected as winapi::shared::windef::HWND, properties);
-            if !result {
-                eprintln!("Failed to add window");
-            }
+            let window = WindowId(selected);
+            wp.add_window_by_handle(window, properties.clone()).map(|_| {
+                let entry = WallpaperEntry {
+                    command: None,
+                    working_dir: None,
+                    selector: selector_for(wp.backend(), window),
+                    properties,
+                    wait: wallpaper::WaitPolicy::default(),
+                };
+                saved = web_view.user_data_mut().remember(window, entry);
+            })
+        },
+        Command::NewFromCustomCommand { command, working_dir, selector, properties, wait } => {
+            // a blank command picks an already running window by selector alone, the engine
+            // reports an error if the selector is empty as well
+            let command = Some(command.trim()).filter(|command| !command.is_empty()).map(str::to_owned);
+            let entry = WallpaperEntry { command, working_dir, selector, properties, wait };
+            entry.apply(wp).map(|window| {
+                saved = web_view.user_data_mut().remember(window, entry);
+            })
         },
-        Command::NewFromCustomCommand { command, selector, properties } => {
-            if !command.trim_start().trim_end().is_empty() {
-                match command_from_str(command) {
-                    Ok(mut command) => {
-                        wp.add_window(Some(&mut command), selector, properties, 50, 100);
-                    },
-                    Err(error) => {
-                        eprintln!("Error parsing command {:?}", error);
+        Command::DetachRunningWallpaper { selected } => {
+            wp.detach_wallpaper(WindowId(selected)).map(|_| {
+                saved = web_view.user_data_mut().forget(WindowId(selected));
+            })
+        },
+        Command::TerminateRunningWallpaper { selected } => {
+            wp.remove_wallpaper(WindowId(selected)).map(|_| {
+                saved = web_view.user_data_mut().forget(WindowId(selected));
+            })
+        },
+        Command::RaiseRunningWallpaper { selected } => {
+            wp.raise(WindowId(selected)).and_then(|_| wp.list_active()).map(|stacking| {
+                saved = web_view.user_data_mut().restack(&stacking);
+            })
+        },
+        Command::LowerRunningWallpaper { selected } => {
+            wp.lower(WindowId(selected)).and_then(|_| wp.list_active()).map(|stacking| {
+                saved = web_view.user_data_mut().restack(&stacking);
+            })
+        },
+        Command::SetRunningWallpaperLayer { selected, layer } => {
+            wp.set_layer(WindowId(selected), layer).and_then(|_| wp.list_active()).map(|stacking| {
+                saved = web_view.user_data_mut().restack(&stacking);
+            })
+        },
+        Command::SuperviseWallpapers {} => {
+            match web_view.user_data_mut().check(wp) {
+                Ok(Some(recovery)) => {
+                    web_view.eval(&format!("window._workerLost({})", recovery.restarted.len()))?;
+                    if recovery.failed.is_empty() {
+                        return Ok(());
                     }
-                }
+                    let failed: Vec<String> = recovery.failed.iter()
+                        .map(|(entry, error)| format!("{}: {}", entry.command.as_deref().unwrap_or("window"), error))
+                        .collect();
+                    return show_error(web_view, &format!("Failed to restart wallpapers: {}", failed.join("; ")));
+                },
+                result => result.map(|_| ()),
             }
         },
-        Command::TerminateRunningWallpaper { selected } => {
-            wp.remove_wallpaper(selected as HWND);
-        }
+        Command::GetAutostart {} => {
+            return web_view.eval(&format!("window._setAutostart({})", autostart::is_enabled()));
+        },
+        Command::SetAutostart { enabled } => {
+            if let Err(error) = autostart::set_enabled(enabled) {
+                show_error(web_view, &format!("Failed to change autostart: {}", error))?;
+            }
+            return web_view.eval(&format!("window._setAutostart({})", autostart::is_enabled()));
+        },
+    };
+
+    match (result, saved) {
+        (Ok(()), Ok(())) => Ok(()),
+        (Err(error), _) => show_error(web_view, &error.to_string()),
+        (_, Err(error)) => show_error(web_view, &error.to_string()),
+    }
+}
+
+/// Selector finding `window` again on the next start: same class and, where known, same executable.
+fn selector_for<B: DesktopBackend>(backend: &B, window: WindowId) -> wallpaper::WindowSelector {
+    let class = wallpaper::WindowSelector::ClassName(backend.window_class(window));
+    match backend.process_path(backend.window_pid(window)) {
+        Some(path) => wallpaper::WindowSelector::All(vec![
+            class, wallpaper::WindowSelector::ExecutablePath(path.to_string_lossy().into_owned()),
+        ]),
+        None => class,
     }
-    Ok(())
 }
 
+/// Connects to the desktop, exiting if there is none.
+#[cfg(any(windows, all(unix, not(target_os = "macos"))))]
+fn native_engine() -> wallpaper::Engine<backend::Native> {
+    match backend::Native::new() {
+        Ok(backend) => wallpaper::Engine::new(backend),
+        Err(error) => {
+            eprintln!("Failed to create wallpaper engine: {}", error);
+            std::process::exit(1);
+        },
+    }
+}
+
+#[cfg(any(windows, all(unix, not(target_os = "macos"))))]
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        cli::attach_console();
+    }
+
+    if !args.is_empty() {
+        // wallpapers added from the command line are meant to outlive the process, so nothing is
+        // restored; backends where they can't refuse such commands
+        let result = cli::parse(&args).and_then(|action| {
+            if action.needs_engine() {
+                cli::execute(&native_engine(), action)
+            } else {
+                cli::execute_standalone(action)
+            }
+        });
+        if let Err(error) = result {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let engine = native_engine();
+    let mut supervisor = Supervisor::load(Config::default_path());
+    supervisor.apply_all(&engine);
     let html_content = include_str!("../html/index.html");
     
     web_view::builder()
@@ -103,8 +251,18 @@ fn main() {
         .size(640, 480)
         .resizable(false)
         .debug(true)
-        .user_data(UserData {})
-        .invoke_handler(handler)
+        .user_data(supervisor)
+        .invoke_handler(|web_view, arg| handler(&engine, web_view, arg))
         .run()
         .unwrap();
+
+    if let Err(error) = engine.restore_all() {
+        eprintln!("Failed to restore windows: {}", error);
+    }
+}
+
+#[cfg(not(any(windows, all(unix, not(target_os = "macos")))))]
+fn main() {
+    eprintln!("wallpaper-rs does not support this platform yet");
+    std::process::exit(1);
 }
@@ -0,0 +1,300 @@
+use std::path::Path;
+
+use regex::{Regex, RegexBuilder};
+use serde::{Serialize, Deserialize};
+
+use crate::backend::{DesktopBackend, WindowId};
+use crate::wallpaper::WallpaperError;
+
+/// Describes which window should become a wallpaper.
+///
+/// Predicates can be combined with `All`, `Any` and `Not`, e.g. in the UI's JSON:
+/// `{"all": [{"className": "Chrome_WidgetWin_1"}, {"not": {"titleContains": {"text": "DevTools"}}}]}`.
+///
+/// `WindowTitle` compares titles exactly, the other title matchers ignore case unless
+/// `case_sensitive` is set. Strings are owned rather than borrowed from the UI message, since
+/// patterns usually contain escaped characters.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum WindowSelector {
+    /// Title is exactly the given text, case included.
+    WindowTitle(String),
+    #[serde(rename_all = "camelCase")]
+    TitleContains {
+        text: String,
+        #[serde(default)]
+        case_sensitive: bool,
+    },
+    #[serde(rename_all = "camelCase")]
+    TitleRegex {
+        pattern: String,
+        #[serde(default)]
+        case_sensitive: bool,
+    },
+    /// Title matches a shell-like pattern where `*` is any text and `?` is any character.
+    #[serde(rename_all = "camelCase")]
+    TitleGlob {
+        pattern: String,
+        #[serde(default)]
+        case_sensitive: bool,
+    },
+    ClassName(String),
+    /// File name of the executable of the owning process, e.g. `mpv.exe`; the extension may
+    /// be left out. Case-insensitive.
+    ProcessName(String),
+    /// Full path to the executable of the owning process.
+    ExecutablePath(String),
+    ProcessId(u32),
+    /// Every selector matches; matches any window when empty.
+    All(Vec<WindowSelector>),
+    /// At least one selector matches; matches nothing when empty.
+    Any(Vec<WindowSelector>),
+    Not(Box<WindowSelector>),
+    None,
+}
+
+/// Converts a glob into an anchored regex.
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+fn compile_regex(pattern: &str, case_sensitive: bool) -> Result<Regex, WallpaperError> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(!case_sensitive)
+        .build()
+        .map_err(|error| WallpaperError::InvalidPattern(error.to_string()))
+}
+
+fn is_process_name(path: &Path, name: &str) -> bool {
+    let matches = |part: Option<&std::ffi::OsStr>| {
+        part.map(|part| part.to_string_lossy().eq_ignore_ascii_case(name)).unwrap_or(false)
+    };
+    matches(path.file_name()) || matches(path.file_stem())
+}
+
+fn is_same_path(a: &Path, b: &Path) -> bool {
+    // NTFS paths are case-insensitive
+    if cfg!(windows) {
+        a.to_string_lossy().eq_ignore_ascii_case(&b.to_string_lossy())
+    } else {
+        a == b
+    }
+}
+
+/// `WindowSelector` prepared for matching many windows.
+#[derive(Debug)]
+pub(crate) enum Matcher<'a> {
+    Title(&'a str),
+    TitleContains { text: String, case_sensitive: bool },
+    TitleRegex(Regex),
+    Class(&'a str),
+    Process(&'a str),
+    Executable(&'a Path),
+    Pid(u32),
+    All(Vec<Matcher<'a>>),
+    Any(Vec<Matcher<'a>>),
+    Not(Box<Matcher<'a>>),
+    Nothing,
+}
+
+impl<'a> Matcher<'a> {
+
+    pub fn new(selector: &'a WindowSelector) -> Result<Matcher<'a>, WallpaperError> {
+        Ok(match selector {
+            WindowSelector::WindowTitle(title) => Matcher::Title(title),
+            WindowSelector::TitleContains { text, case_sensitive } => Matcher::TitleContains {
+                text: if *case_sensitive { text.clone() } else { text.to_lowercase() },
+                case_sensitive: *case_sensitive,
+            },
+            WindowSelector::TitleRegex { pattern, case_sensitive } => {
+                Matcher::TitleRegex(compile_regex(pattern, *case_sensitive)?)
+            },
+            WindowSelector::TitleGlob { pattern, case_sensitive } => {
+                Matcher::TitleRegex(compile_regex(&glob_to_regex(pattern), *case_sensitive)?)
+            },
+            WindowSelector::ClassName(class) => Matcher::Class(class),
+            WindowSelector::ProcessName(name) => Matcher::Process(name),
+            WindowSelector::ExecutablePath(path) => Matcher::Executable(Path::new(path)),
+            WindowSelector::ProcessId(pid) => Matcher::Pid(*pid),
+            WindowSelector::All(selectors) => {
+                Matcher::All(selectors.iter().map(Matcher::new).collect::<Result<_, _>>()?)
+            },
+            WindowSelector::Any(selectors) => {
+                Matcher::Any(selectors.iter().map(Matcher::new).collect::<Result<_, _>>()?)
+            },
+            WindowSelector::Not(selector) => Matcher::Not(Box::new(Matcher::new(selector)?)),
+            WindowSelector::None => Matcher::Nothing,
+        })
+    }
+
+    pub fn matches<B: DesktopBackend>(&self, backend: &B, window: WindowId) -> bool {
+        match self {
+            Matcher::Title(title) => backend.window_title(window) == *title,
+            Matcher::TitleContains { text, case_sensitive: true } => backend.window_title(window).contains(text.as_str()),
+            Matcher::TitleContains { text, case_sensitive: false } => {
+                backend.window_title(window).to_lowercase().contains(text.as_str())
+            },
+            Matcher::TitleRegex(regex) => regex.is_match(&backend.window_title(window)),
+            Matcher::Class(class) => backend.window_class(window) == *class,
+            Matcher::Process(name) => {
+                let path = backend.process_path(backend.window_pid(window));
+                path.map(|path| is_process_name(&path, name)).unwrap_or(false)
+            },
+            Matcher::Executable(expected) => {
+                let path = backend.process_path(backend.window_pid(window));
+                path.map(|path| is_same_path(&path, expected)).unwrap_or(false)
+            },
+            Matcher::Pid(pid) => backend.window_pid(window) == *pid,
+            Matcher::All(matchers) => matchers.iter().all(|matcher| matcher.matches(backend, window)),
+            Matcher::Any(matchers) => matchers.iter().any(|matcher| matcher.matches(backend, window)),
+            Matcher::Not(matcher) => !matcher.matches(backend, window),
+            Matcher::Nothing => false,
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::fake::{FakeDesktop, FakeWindow};
+    use crate::wallpaper::WallpaperError;
+    use super::{glob_to_regex, Matcher, WindowSelector};
+
+    fn matching(selector: WindowSelector, titles: &[&str]) -> Vec<String> {
+        let desktop = FakeDesktop::new();
+        let matcher = Matcher::new(&selector).unwrap();
+        titles.iter()
+            .map(|title| desktop.add(FakeWindow::new(title, "Class", 1)))
+            .filter(|&window| matcher.matches(&desktop, window))
+            .map(|window| desktop.window(window).title)
+            .collect()
+    }
+
+    const TITLES: &[&str] = &["video.mp4 - mpv", "(3) Chat - Firefox", "chat.txt - Notepad"];
+
+    #[test]
+    fn title_contains() {
+        let selector = WindowSelector::TitleContains { text: "chat".to_owned(), case_sensitive: false };
+        assert_eq!(matching(selector, TITLES), ["(3) Chat - Firefox", "chat.txt - Notepad"]);
+
+        let selector = WindowSelector::TitleContains { text: "chat".to_owned(), case_sensitive: true };
+        assert_eq!(matching(selector, TITLES), ["chat.txt - Notepad"]);
+    }
+
+    #[test]
+    fn title_regex() {
+        let selector = WindowSelector::TitleRegex { pattern: r"^\(\d+\) chat".to_owned(), case_sensitive: false };
+        assert_eq!(matching(selector, TITLES), ["(3) Chat - Firefox"]);
+
+        let selector = WindowSelector::TitleRegex { pattern: "Mpv$".to_owned(), case_sensitive: true };
+        assert!(matching(selector, TITLES).is_empty());
+    }
+
+    #[test]
+    fn title_glob() {
+        let selector = WindowSelector::TitleGlob { pattern: "*.mp? - MPV".to_owned(), case_sensitive: false };
+        assert_eq!(matching(selector, TITLES), ["video.mp4 - mpv"]);
+
+        let selector = WindowSelector::TitleGlob { pattern: "chat*".to_owned(), case_sensitive: false };
+        assert_eq!(matching(selector, TITLES), ["chat.txt - Notepad"]);
+    }
+
+    #[test]
+    fn process_name_and_executable() {
+        let desktop = FakeDesktop::new();
+        let player = desktop.add(FakeWindow::new("video.mp4 - mpv", "mpv", 42));
+        let editor = desktop.add(FakeWindow::new("notes", "Notepad", 43));
+        let unknown = desktop.add(FakeWindow::new("unknown", "Unknown", 44));
+        desktop.set_process(42, "/usr/bin/mpv.exe");
+        desktop.set_process(43, "/usr/bin/notepad");
+
+        let windows = [player, editor, unknown];
+        let matching = |selector: WindowSelector| {
+            let matcher = Matcher::new(&selector).unwrap();
+            windows.iter().cloned().filter(|&window| matcher.matches(&desktop, window)).collect::<Vec<_>>()
+        };
+
+        assert_eq!(matching(WindowSelector::ProcessName("MPV".to_owned())), [player]);
+        assert_eq!(matching(WindowSelector::ProcessName("mpv.exe".to_owned())), [player]);
+        assert_eq!(matching(WindowSelector::ProcessName("bin".to_owned())), []);
+        assert_eq!(matching(WindowSelector::ExecutablePath("/usr/bin/notepad".to_owned())), [editor]);
+        assert_eq!(matching(WindowSelector::ExecutablePath("notepad".to_owned())), []);
+    }
+
+    #[test]
+    fn boolean_combinations() {
+        let json = r#"{"all": [
+            {"className": "Chrome_WidgetWin_1"},
+            {"titleContains": {"text": "dashboard"}},
+            {"not": {"titleContains": {"text": "DevTools"}}}
+        ]}"#;
+        let selector: WindowSelector = serde_json::from_str(json).unwrap();
+
+        let desktop = FakeDesktop::new();
+        let dashboard = desktop.add(FakeWindow::new("Grafana Dashboard - Chrome", "Chrome_WidgetWin_1", 1));
+        let devtools = desktop.add(FakeWindow::new("DevTools - dashboard.local", "Chrome_WidgetWin_1", 1));
+        let other = desktop.add(FakeWindow::new("dashboard.txt - Notepad", "Notepad", 2));
+
+        let matcher = Matcher::new(&selector).unwrap();
+        assert!(matcher.matches(&desktop, dashboard));
+        assert!(!matcher.matches(&desktop, devtools));
+        assert!(!matcher.matches(&desktop, other));
+
+        let selector = WindowSelector::Any(vec![WindowSelector::ProcessId(2), WindowSelector::ClassName("mpv".to_owned())]);
+        let matcher = Matcher::new(&selector).unwrap();
+        assert!(matcher.matches(&desktop, other));
+        assert!(!matcher.matches(&desktop, dashboard));
+
+        assert!(Matcher::new(&WindowSelector::All(vec![])).unwrap().matches(&desktop, other));
+        assert!(!Matcher::new(&WindowSelector::Any(vec![])).unwrap().matches(&desktop, other));
+    }
+
+    #[test]
+    fn invalid_pattern_in_tree_is_an_error() {
+        let selector = WindowSelector::Not(Box::new(WindowSelector::Any(vec![
+            WindowSelector::ClassName("mpv".to_owned()),
+            WindowSelector::TitleRegex { pattern: "[".to_owned(), case_sensitive: false },
+        ])));
+        match Matcher::new(&selector) {
+            Err(WallpaperError::InvalidPattern(_)) => {},
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn glob_escapes_regex_syntax() {
+        assert_eq!(glob_to_regex("(3) *.txt"), r"^\(3\) .*\.txt$");
+    }
+
+    #[test]
+    fn invalid_regex_is_an_error() {
+        match Matcher::new(&WindowSelector::TitleRegex { pattern: "(".to_owned(), case_sensitive: true }) {
+            Err(WallpaperError::InvalidPattern(_)) => {},
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn deserializes_from_ui_json() {
+        let json = r#"{"titleRegex": {"pattern": "^\\(\\d+\\)", "caseSensitive": true}}"#;
+        match serde_json::from_str(json).unwrap() {
+            WindowSelector::TitleRegex { pattern, case_sensitive: true } => assert_eq!(pattern, r"^\(\d+\)"),
+            selector => panic!("Unexpected selector: {:?}", selector),
+        }
+
+        let json = r#"{"titleContains": {"text": "chat"}}"#;
+        match serde_json::from_str(json).unwrap() {
+            WindowSelector::TitleContains { text, case_sensitive: false } => assert_eq!(text, "chat"),
+            selector => panic!("Unexpected selector: {:?}", selector),
+        }
+    }
+}
@@ -0,0 +1,249 @@
+//! Keeps the wallpapers of the config running, starting them again when the desktop loses them.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::backend::{DesktopBackend, WindowId};
+use crate::config::{Config, ConfigError, WallpaperEntry};
+use crate::wallpaper::{Engine, EngineEvent, WallpaperError};
+
+/// How often the wallpaper layer is checked when running without the UI.
+pub const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// What `Supervisor::check` did after the wallpaper layer was lost.
+#[derive(Debug)]
+pub struct Recovery {
+    /// Wallpapers of the config which were destroyed with the layer and started again.
+    pub restarted: Vec<WindowId>,
+    /// Wallpapers of the config which could not be started again. They stay in the config.
+    pub failed: Vec<(WallpaperEntry, WallpaperError)>,
+}
+
+#[derive(Debug)]
+pub struct Supervisor {
+    config: Config,
+    /// Where `config` is saved, `None` if it couldn't be loaded and mustn't be overwritten.
+    config_path: Option<PathBuf>,
+    /// Config entries of the wallpapers running now.
+    entries: HashMap<WindowId, WallpaperEntry>,
+}
+
+impl Supervisor {
+
+    pub fn new(config: Config, config_path: Option<PathBuf>) -> Supervisor {
+        Supervisor { config, config_path, entries: HashMap::new() }
+    }
+
+    /// Loads the config at `path`. When it can't be read the supervisor starts out empty and
+    /// never saves, so that a config with a typo in it isn't replaced.
+    pub fn load(path: Option<PathBuf>) -> Supervisor {
+        match path.as_ref().map(|path| Config::load(path)).transpose() {
+            Ok(config) => Supervisor::new(config.unwrap_or_default(), path),
+            Err(error) => {
+                eprintln!("{}", error);
+                Supervisor::new(Config::default(), None)
+            },
+        }
+    }
+
+    /// Sets up every wallpaper of the config. Entries which fail stay in the config, e.g. for
+    /// an application that isn't running right now.
+    pub fn apply_all<B: DesktopBackend>(&mut self, engine: &Engine<B>) {
+        for entry in &self.config.wallpapers {
+            match entry.apply(engine) {
+                Ok(window) => { self.entries.insert(window, entry.clone()); },
+                Err(error) => eprintln!("Failed to set up wallpaper {:?}: {}", entry.command, error),
+            }
+        }
+    }
+
+    /// Adds a wallpaper set up as `window` to the config.
+    pub fn remember(&mut self, window: WindowId, entry: WallpaperEntry) -> Result<(), ConfigError> {
+        self.config.wallpapers.push(entry.clone());
+        self.entries.insert(window, entry);
+        self.save()
+    }
+
+    /// Removes the wallpaper set up as `window` from the config.
+    pub fn forget(&mut self, window: WindowId) -> Result<(), ConfigError> {
+        let entry = match self.entries.remove(&window) {
+            Some(entry) => entry,
+            None => return Ok(()),
+        };
+        if let Some(index) = self.config.wallpapers.iter().position(|saved| *saved == entry) {
+            self.config.wallpapers.remove(index);
+        }
+        self.save()
+    }
+
+    /// Orders the config like `stacking`, the running wallpapers from bottom to top, so that they
+    /// are stacked the same way on the next start. Entries which aren't running go to the bottom.
+    pub fn restack(&mut self, stacking: &[WindowId]) -> Result<(), ConfigError> {
+        let entries = &self.entries;
+        let layers: Vec<&WallpaperEntry> = stacking.iter().filter_map(|window| entries.get(window)).collect();
+        self.config.wallpapers.sort_by_key(|entry| layers.iter().position(|layer| *layer == entry));
+        self.save()
+    }
+
+    /// Checks that the wallpaper layer is still valid. If it was lost, e.g. when explorer.exe
+    /// restarted, now or during an earlier operation of the engine, starts the wallpapers
+    /// destroyed with it again. Also places the wallpapers again when the displays changed.
+    pub fn check<B: DesktopBackend>(&mut self, engine: &Engine<B>) -> Result<Option<Recovery>, WallpaperError> {
+        engine.recover_layer()?;
+        engine.check_displays()?;
+        let mut lost = false;
+        let mut destroyed = Vec::new();
+        for event in engine.take_events() {
+            match event {
+                EngineEvent::WorkerLost { destroyed: windows } => {
+                    lost = true;
+                    destroyed.extend(windows);
+                },
+                EngineEvent::DisplaysChanged { .. } => {},
+            }
+        }
+        if !lost {
+            return Ok(None);
+        }
+
+        let mut recovery = Recovery { restarted: Vec::new(), failed: Vec::new() };
+        for window in destroyed {
+            let entry = match self.entries.remove(&window) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            match entry.apply(engine) {
+                Ok(window) => {
+                    self.entries.insert(window, entry);
+                    recovery.restarted.push(window);
+                },
+                Err(error) => recovery.failed.push((entry, error)),
+            }
+        }
+        Ok(Some(recovery))
+    }
+
+    fn save(&self) -> Result<(), ConfigError> {
+        match &self.config_path {
+            Some(path) => self.config.save(path),
+            None => Ok(()),
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::{Monitor, Rect};
+    use crate::backend::fake::{FakeDesktop, FakeWindow};
+    use crate::config::{Config, WallpaperEntry};
+    use crate::geometry::DEFAULT_DPI;
+    use crate::wallpaper::{Engine, WaitPolicy, WallpaperProperties, WindowSelector};
+    use super::Supervisor;
+
+    fn entry(command: &str) -> WallpaperEntry {
+        WallpaperEntry {
+            command: Some(command.to_owned()),
+            working_dir: None,
+            selector: WindowSelector::None,
+            properties: WallpaperProperties { fullscreen: true, ..WallpaperProperties::default() },
+            wait: WaitPolicy { timeout_ms: 0, ..WaitPolicy::default() },
+        }
+    }
+
+    #[test]
+    fn check_does_nothing_while_layer_is_valid() {
+        let engine = Engine::new(FakeDesktop::new());
+        engine.backend().on_spawn(vec![FakeWindow::new("video.mp4 - mpv", "mpv", 0)], 0);
+        let mut supervisor = Supervisor::new(Config { wallpapers: vec![entry("mpv video.mp4")] }, None);
+        supervisor.apply_all(&engine);
+
+        assert!(supervisor.check(&engine).unwrap().is_none());
+        assert_eq!(engine.backend().spawned(), ["mpv"]);
+    }
+
+    #[test]
+    fn check_restarts_destroyed_wallpapers() {
+        let engine = Engine::new(FakeDesktop::new());
+        engine.backend().on_spawn(vec![FakeWindow::new("video.mp4 - mpv", "mpv", 0)], 0);
+        let mut supervisor = Supervisor::new(Config { wallpapers: vec![entry("mpv video.mp4")] }, None);
+        supervisor.apply_all(&engine);
+        let untracked = engine.backend().add(FakeWindow::new("clock", "Clock", 42));
+        engine.add_window_by_handle(untracked, WallpaperProperties::default()).unwrap();
+
+        engine.backend().on_spawn(vec![FakeWindow::new("video.mp4 - mpv", "mpv", 0)], 0);
+        engine.backend().destroy_layer();
+        let recovery = supervisor.check(&engine).unwrap().unwrap();
+
+        assert_eq!(engine.backend().spawned(), ["mpv", "mpv"]);
+        assert_eq!(engine.backend().layer_changes(), 1);
+        assert_eq!(engine.list_active().unwrap(), recovery.restarted);
+        assert!(recovery.failed.is_empty());
+        assert!(supervisor.check(&engine).unwrap().is_none());
+    }
+
+    #[test]
+    fn check_restarts_wallpapers_lost_during_other_operations() {
+        let engine = Engine::new(FakeDesktop::new());
+        engine.backend().on_spawn(vec![FakeWindow::new("video.mp4 - mpv", "mpv", 0)], 0);
+        let mut supervisor = Supervisor::new(Config { wallpapers: vec![entry("mpv video.mp4")] }, None);
+        supervisor.apply_all(&engine);
+
+        engine.backend().on_spawn(vec![FakeWindow::new("video.mp4 - mpv", "mpv", 0)], 0);
+        engine.backend().destroy_layer();
+        assert!(engine.list_active().unwrap().is_empty());
+        let recovery = supervisor.check(&engine).unwrap().unwrap();
+
+        assert_eq!(engine.backend().layer_changes(), 1);
+        assert_eq!(engine.list_active().unwrap(), recovery.restarted);
+    }
+
+    #[test]
+    fn check_places_wallpapers_again_when_displays_change() {
+        let engine = Engine::new(FakeDesktop::new());
+        engine.backend().on_spawn(vec![FakeWindow::new("video.mp4 - mpv", "mpv", 0)], 0);
+        let mut supervisor = Supervisor::new(Config { wallpapers: vec![entry("mpv video.mp4")] }, None);
+        supervisor.apply_all(&engine);
+        let window = engine.list_active().unwrap()[0];
+
+        let screen = Rect { left: 0, top: 0, right: 2560, bottom: 1440 };
+        engine.backend().set_monitors(vec![
+            Monitor { name: "DISPLAY1".to_owned(), primary: true, rect: screen, work_area: screen, dpi: DEFAULT_DPI },
+        ]);
+
+        assert!(supervisor.check(&engine).unwrap().is_none());
+        assert_eq!(engine.backend().window(window).rect, screen);
+        assert_eq!(engine.backend().spawned(), ["mpv"]);
+    }
+
+    #[test]
+    fn restack_orders_config_like_wallpapers() {
+        let engine = Engine::new(FakeDesktop::new());
+        let config = Config { wallpapers: vec![entry("mpv video.mp4"), entry("clock"), entry("missing")] };
+        engine.backend().on_spawn(vec![FakeWindow::new("video.mp4 - mpv", "mpv", 0)], 0);
+        engine.backend().on_spawn(vec![FakeWindow::new("clock", "Clock", 0)], 0);
+        let mut supervisor = Supervisor::new(config, None);
+        supervisor.apply_all(&engine);
+        let video = engine.list_active().unwrap()[0];
+
+        engine.raise(video).unwrap();
+        supervisor.restack(&engine.list_active().unwrap()).unwrap();
+
+        assert_eq!(supervisor.config.wallpapers, [entry("missing"), entry("clock"), entry("mpv video.mp4")]);
+    }
+
+    #[test]
+    fn check_keeps_wallpapers_which_fail_to_restart() {
+        let engine = Engine::new(FakeDesktop::new());
+        engine.backend().on_spawn(vec![FakeWindow::new("video.mp4 - mpv", "mpv", 0)], 0);
+        let mut supervisor = Supervisor::new(Config { wallpapers: vec![entry("mpv video.mp4")] }, None);
+        supervisor.apply_all(&engine);
+
+        engine.backend().destroy_layer();
+        let recovery = supervisor.check(&engine).unwrap().unwrap();
+
+        assert!(recovery.restarted.is_empty());
+        assert_eq!(recovery.failed.len(), 1);
+        assert_eq!(supervisor.config.wallpapers, [entry("mpv video.mp4")]);
+    }
+}
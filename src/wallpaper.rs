@@ -1,555 +1,1521 @@
-use std::ffi::{OsStr, OsString};
-use std::iter::once;
-use std::os::windows::ffi::{OsStrExt, OsStringExt};
-use std::ptr::null_mut;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::process::Command;
+use std::time::{Duration, Instant};
 
 use serde::{Serialize, Deserialize};
 
-use winapi::shared::windef::{HWND, RECT, PPOINT, POINT};
-use winapi::shared::minwindef::LPARAM;
-use winapi::um::errhandlingapi::{GetLastError, SetLastError};
-use winapi::um::winuser::{
-    GetClassNameW, IsChild, ShowWindow, GetWindowRect, MapWindowPoints,
-    MonitorFromPoint, GetMonitorInfoW, SetWindowPos,
-    SW_SHOW, 
-    MONITOR_DEFAULTTONEAREST,
-    MONITORINFO
-};
-
-fn find_window_by_class(class: &str) -> HWND {
-    use winapi::um::winuser::FindWindowW;
-    unsafe { FindWindowW(to_wide(class).as_ptr(), null_mut()) }
+use crate::backend::{DesktopBackend, Monitor, Rect, WindowId};
+use crate::geometry::{self, DEFAULT_DPI};
+use crate::selector::Matcher;
+
+pub use crate::geometry::{Anchor, Margins, Scaling};
+pub use crate::selector::WindowSelector;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WallpaperProperties {
+    pub fullscreen: bool,
+    /// Monitor to put the wallpaper on; the one it is already on if not set.
+    #[serde(default)]
+    pub monitor: Option<MonitorSelector>,
+    /// Cover the bounding box of all monitors, for panoramic wallpapers. Takes precedence over
+    /// `fullscreen` and `monitor`.
+    #[serde(default)]
+    pub span: bool,
+    /// Exact position on the monitor, e.g. for widgets. Takes precedence over `fullscreen`.
+    #[serde(default)]
+    pub placement: Option<Placement>,
+    /// Lay out `placement` and `fullscreen` in the work area of the monitor, leaving taskbars
+    /// and docked toolbars uncovered.
+    #[serde(default)]
+    pub work_area: bool,
+    /// How `fullscreen` and `span` deal with the aspect ratio of the window.
+    #[serde(default)]
+    pub scaling: Scaling,
+}
+
+/// Position of a wallpaper relative to the top-left corner of its monitor or work area, in
+/// logical pixels which are scaled by the scale factor of the monitor.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Placement {
+    /// An exact rectangle, e.g. `{"rect": {"left": 20, "top": 20, "right": 420, "bottom": 220}}`.
+    Rect(Rect),
+    /// A window of the given size put at `anchor`, kept `margins` away from the edges.
+    #[serde(rename_all = "camelCase")]
+    Anchored {
+        width: i32,
+        height: i32,
+        anchor: Anchor,
+        #[serde(default)]
+        margins: Margins,
+    },
+}
+
+impl Placement {
+
+    /// Screen rectangle of a wallpaper placed in `area` of a monitor with `dpi`, given in screen
+    /// coordinates.
+    pub fn resolve(&self, area: Rect, dpi: u32) -> Rect {
+        let scale = |length| geometry::rescale(length, DEFAULT_DPI, dpi);
+        match *self {
+            Placement::Rect(rect) => rect.rescale(DEFAULT_DPI, dpi).offset(area.left, area.top),
+            Placement::Anchored { width, height, anchor, margins } => {
+                geometry::anchored(scale(width), scale(height), anchor, area.inset(margins.rescale(DEFAULT_DPI, dpi)))
+            },
+        }
+    }
+
 }
 
-fn to_wide(s: &str) -> Vec<u16> {
-    OsStr::new(s).encode_wide().chain(once(0)).collect()
+/// Picks a monitor by its index in `Engine::list_monitors` or by its name, e.g. `1` or `"HDMI-1"`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MonitorSelector {
+    Index(usize),
+    Name(String),
 }
 
-pub fn get_window_name(hwnd: HWND) -> String {
-    use winapi::um::winuser::{GetWindowTextLengthW, GetWindowTextW};
+impl MonitorSelector {
 
-    if hwnd.is_null() {
-        panic!("Invalid HWND");
+    /// Index of the selected monitor in `monitors`; names are compared ignoring case.
+    pub fn position(&self, monitors: &[Monitor]) -> Option<usize> {
+        match self {
+            MonitorSelector::Index(index) => Some(*index).filter(|&index| index < monitors.len()),
+            MonitorSelector::Name(name) => monitors.iter().position(|monitor| monitor.name.eq_ignore_ascii_case(name)),
+        }
     }
 
-    let text = unsafe {
-        let text_length = GetWindowTextLengthW(hwnd);
-        let mut text: Vec<u16> = vec![0; text_length as usize + 1];
-        GetWindowTextW(hwnd, text.as_mut_ptr(), text_length + 1);
-        OsString::from_wide(&text[..text.iter().position(|&c| c == 0).unwrap()])
-    };
+}
+
+impl From<&str> for MonitorSelector {
+    fn from(text: &str) -> MonitorSelector {
+        match text.parse() {
+            Ok(index) => MonitorSelector::Index(index),
+            Err(_) => MonitorSelector::Name(text.to_owned()),
+        }
+    }
+}
+
+/// How long and how often `Engine::add_window` looks for the window. Times are in milliseconds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct WaitPolicy {
+    /// Give up once this much time was spent waiting.
+    pub timeout_ms: u64,
+    /// Wait before the first lookup, for applications known to start slowly.
+    pub initial_delay_ms: u64,
+    /// Delay between the first two lookups.
+    pub interval_ms: u64,
+    /// Every following delay is this many times longer...
+    pub backoff: f64,
+    /// ...but never longer than this.
+    pub max_interval_ms: u64,
+    /// Skip windows which have zero width or height, e.g. ones still being laid out.
+    pub require_size: bool,
+    /// Accept a window only once its title has not changed for this long, so that a player
+    /// showing "Loading..." isn't picked up by a selector meant for its final title.
+    pub stable_title_ms: u64,
+}
+
+impl Default for WaitPolicy {
+    fn default() -> WaitPolicy {
+        WaitPolicy {
+            timeout_ms: 5000,
+            initial_delay_ms: 0,
+            interval_ms: 50,
+            backoff: 1.5,
+            max_interval_ms: 1000,
+            require_size: true,
+            stable_title_ms: 0,
+        }
+    }
+}
+
+/// Something which happened to the desktop behind the engine's back.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EngineEvent {
+    /// The wallpaper layer (`WorkerW` on Windows) was gone and had to be found or created again.
+    /// `destroyed` are the wallpapers which went away together with it.
+    WorkerLost { destroyed: Vec<WindowId> },
+    /// Monitors were added, removed or changed resolution or scale, and `moved` are the
+    /// wallpapers which were placed again for the new configuration.
+    DisplaysChanged { moved: Vec<WindowId> },
+}
+
+#[derive(Debug)]
+pub enum WallpaperError {
+    #[cfg(windows)]
+    ProgmanNotFound,
+    DisplayNotFound,
+    #[cfg(all(unix, not(target_os = "macos"), feature = "wayland"))]
+    LayerShellUnsupported,
+    #[cfg(any(windows, all(unix, not(target_os = "macos"), not(feature = "wayland"))))]
+    UnableToSpawnWorker,
+    WindowNotFound,
+    MonitorNotFound,
+    AlreadyAttached,
+    NotAttached,
+    ForbiddenWindow(String),
+    #[cfg(any(windows, all(unix, not(target_os = "macos"), not(feature = "wayland"))))]
+    ReparentFailed { os_code: u32 },
+    SpawnFailed(std::io::Error),
+    /// A command line has mismatched quotes.
+    InvalidCommand(String),
+    /// Neither a command to spawn nor a selector to look for was given.
+    NothingToSelect,
+    SelectorTimedOut,
+    /// A title regex or glob could not be compiled.
+    InvalidPattern(String),
+    /// The operation can't be done with the current backend.
+    Unsupported,
+    /// A call to the windowing system failed. `os_code` is `GetLastError` on Windows and the
+    /// protocol error code on X11.
+    #[cfg(any(windows, all(unix, not(target_os = "macos"), not(feature = "wayland"))))]
+    Os { call: &'static str, os_code: u32 },
+}
 
-    text.into_string().expect("Failed to convert string to UTF-8")
+impl fmt::Display for WallpaperError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            #[cfg(windows)]
+            WallpaperError::ProgmanNotFound => write!(f, "Progman window not found"),
+            WallpaperError::DisplayNotFound => write!(f, "Can't connect to the display server"),
+            #[cfg(all(unix, not(target_os = "macos"), feature = "wayland"))]
+            WallpaperError::LayerShellUnsupported => write!(f, "Compositor does not support wlr-layer-shell"),
+            #[cfg(any(windows, all(unix, not(target_os = "macos"), not(feature = "wayland"))))]
+            WallpaperError::UnableToSpawnWorker => write!(f, "Unable to spawn wallpaper worker window"),
+            WallpaperError::WindowNotFound => write!(f, "Window not found"),
+            WallpaperError::MonitorNotFound => write!(f, "Monitor not found"),
+            WallpaperError::AlreadyAttached => write!(f, "Window is already a wallpaper"),
+            WallpaperError::NotAttached => write!(f, "Window is not a wallpaper"),
+            WallpaperError::ForbiddenWindow(class) => write!(f, "Windows of class '{}' can't be wallpapers", class),
+            #[cfg(any(windows, all(unix, not(target_os = "macos"), not(feature = "wayland"))))]
+            WallpaperError::ReparentFailed { os_code } => write!(f, "Failed to reparent window, error {}", os_code),
+            WallpaperError::SpawnFailed(error) => write!(f, "Failed to start command: {}", error),
+            WallpaperError::InvalidCommand(command) => write!(f, "Command '{}' is empty or has mismatched quotes", command),
+            WallpaperError::NothingToSelect => write!(f, "One or both of selector and command should be specified"),
+            WallpaperError::SelectorTimedOut => write!(f, "No window matched the selector in time"),
+            WallpaperError::InvalidPattern(error) => write!(f, "Invalid pattern: {}", error),
+            WallpaperError::Unsupported => write!(f, "Not supported on this platform"),
+            #[cfg(any(windows, all(unix, not(target_os = "macos"), not(feature = "wayland"))))]
+            WallpaperError::Os { call, os_code } => write!(f, "{} failed, error {}", call, os_code),
+        }
+    }
 }
 
-/**
- * Spawn a wallpaper window if it doesn't already exists and return handle to it.
- * 
- * `progman` - a valid handle to the `Progman`.
- * 
- * This function is unsafe, because user is responsible for providing valid progman handle.
- */
-unsafe fn find_or_spawn_worker(progman: HWND) -> HWND {
-    use winapi::um::winuser::{SendMessageW, EnumWindows};
+impl std::error::Error for WallpaperError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WallpaperError::SpawnFailed(error) => Some(error),
+            _ => None,
+        }
+    }
+}
 
-    extern "system" fn find_worker(hwnd: HWND, data: LPARAM) -> i32 {
-        use winapi::um::winuser::FindWindowExW;
+/// A spawned process and every descendant of it seen so far.
+///
+/// Descendants are remembered because launchers often exit right after starting the real
+/// application, after which the application can no longer be traced back to them.
+#[derive(Debug)]
+struct ProcessTree {
+    pids: HashSet<u32>,
+}
 
-        let data = data as *mut UserData;
+impl ProcessTree {
 
-        unsafe {
-            if FindWindowExW(hwnd, null_mut(), (*data).shell_class.as_ptr(), null_mut()).is_null() {
-                return 1;
+    fn new(root: u32) -> ProcessTree {
+        ProcessTree { pids: [root].iter().cloned().collect() }
+    }
+
+    /// Checks whether `pid` belongs to the tree, walking up `parents` from it.
+    fn contains(&mut self, pid: u32, parents: &HashMap<u32, u32>) -> bool {
+        let mut ancestors = Vec::new();
+        let mut current = pid;
+        // bounded, as pid reuse can produce cycles
+        while ancestors.len() < 64 {
+            if self.pids.contains(&current) {
+                self.pids.extend(ancestors);
+                return true;
             }
-            
-            let worker = FindWindowExW(null_mut(), hwnd, (*data).worker_class.as_ptr(), null_mut());
-            if worker.is_null() {
-                return 1;
+            ancestors.push(current);
+            match parents.get(&current) {
+                Some(&parent) if parent != 0 && parent != current => current = parent,
+                _ => return false,
             }
+        }
+        false
+    }
+
+}
+
+/// What the engine keeps about a window it attached.
+#[derive(Debug)]
+struct Wallpaper<S> {
+    /// State of the window from before it was attached.
+    saved: S,
+    /// Size of the window before it was attached in logical pixels, which `Scaling` keeps.
+    native_size: (i32, i32),
+    /// Where the window was put last, to put it there again when the displays change.
+    properties: WallpaperProperties,
+}
+
+#[derive(Debug)]
+pub struct Engine<B: DesktopBackend> {
+    backend: B,
+    /// Every window attached by this engine.
+    wallpapers: RefCell<HashMap<WindowId, Wallpaper<B::SavedWindow>>>,
+    /// Events not yet collected by `take_events`.
+    events: RefCell<Vec<EngineEvent>>,
+    /// Monitors as of the last `check_displays`.
+    monitors: RefCell<Vec<Monitor>>,
+    /// Windows in the wallpaper layer from bottom to top, as arranged by this engine.
+    layers: RefCell<Vec<WindowId>>,
+}
+
+impl<B: DesktopBackend> Engine<B> {
 
-            (*data).worker = worker;
-            (*data).parent = hwnd;    
+    pub fn new(backend: B) -> Engine<B> {
+        let monitors = backend.monitors().unwrap_or_default();
+        Engine {
+            backend,
+            wallpapers: RefCell::new(HashMap::new()),
+            events: RefCell::new(Vec::new()),
+            monitors: RefCell::new(monitors),
+            layers: RefCell::new(Vec::new()),
         }
+    }
 
-        return 0;
+    pub fn backend(&self) -> &B {
+        &self.backend
     }
 
-    struct UserData {
-        shell_class: Vec<u16>,
-        worker_class: Vec<u16>,
-        worker: HWND,
-        parent: HWND,
+    pub fn list_windows(&self) -> Result<Vec<WindowId>, WallpaperError> {
+        self.backend.list_windows()
+    }
+
+    /// Lists wallpapers from bottom to top.
+    pub fn list_active(&self) -> Result<Vec<WindowId>, WallpaperError> {
+        self.recover_layer()?;
+        self.stacking()
     }
-    
-    let mut user_data = UserData {
-        shell_class: to_wide("SHELLDLL_DefView"),
-        worker_class: to_wide("WorkerW"),
-        worker: null_mut(),
-        parent: null_mut(),
-    };
 
-    SetLastError(0);
-    EnumWindows(Some(find_worker), &mut user_data as *mut UserData as LPARAM);
-    if GetLastError() != 0 {
-        panic!("EnumWindows failed, GetLastError says: '{}'", GetLastError());
-    }
-
-    if user_data.worker.is_null() {
-        // this is basically all the magic. it's an undocumented window message that
-        // forces windows to spawn a window with class "WorkerW" behind deskicons
-        SendMessageW(progman, 0x052C, 0xD, 0);
-        SendMessageW(progman, 0x052C, 0xD, 1);
-        
-        SetLastError(0);
-        EnumWindows(Some(find_worker), &mut user_data as *mut UserData as LPARAM);
-        if GetLastError() != 0 {
-            panic!("EnumWindows failed, GetLastError says: '{}'", GetLastError());
-        }
-
-        if user_data.worker.is_null() {
-            eprintln!("W: couldn't spawn WorkerW window, trying old method");
-    
-            SendMessageW(progman, 0x052C, 0, 0);
-
-            SetLastError(0);
-            EnumWindows(Some(find_worker), &mut user_data as *mut UserData as LPARAM);
-            if GetLastError() != 0 {
-                panic!("EnumWindows failed, GetLastError says: '{}'", GetLastError());
+    /// Brings the kept stacking order up to date: windows which left the layer are dropped, and
+    /// windows attached by someone else are put on top in the order the backend lists them.
+    fn stacking(&self) -> Result<Vec<WindowId>, WallpaperError> {
+        let attached = self.backend.list_attached()?;
+        let mut layers = self.layers.borrow_mut();
+        layers.retain(|window| attached.contains(window));
+        for window in attached {
+            if !layers.contains(&window) {
+                layers.push(window);
             }
         }
-    
+        Ok(layers.clone())
     }
 
-    user_data.worker
-}
+    pub fn add_window_by_handle(
+        &self, handle: WindowId, properties: WallpaperProperties
+    ) -> Result<(), WallpaperError> {
+        if !self.backend.window_exists(handle) {
+            return Err(WallpaperError::WindowNotFound);
+        }
+        self.recover_layer()?;
+        self.check_displays()?;
 
-unsafe fn get_window_style(hwnd: HWND) -> (i32, i32) {
-    use winapi::um::winuser::{GetWindowLongW, GWL_STYLE, GWL_EXSTYLE};
+        let class = self.backend.window_class(handle);
+        if self.backend.is_wallpaper_layer(handle) || B::FORBIDDEN_CLASSES.contains(&class.as_str()) {
+            return Err(WallpaperError::ForbiddenWindow(class));
+        }
 
-    SetLastError(0);
-    let style = GetWindowLongW(hwnd, GWL_STYLE);
-    let ex_style = GetWindowLongW(hwnd, GWL_EXSTYLE);
+        if self.backend.is_attached(handle) {
+            return Err(WallpaperError::AlreadyAttached);
+        }
 
-    if (style == 0 || ex_style == 0) && GetLastError() != 0 {
-        panic!("GetWindowLongW failed, GetLastError says: '{}'", GetLastError());
+        // worked out up front so that a missing monitor doesn't leave the window half-attached;
+        // attaching keeps the window where it is on the screen
+        let rect = self.backend.window_rect(handle)?;
+        let native_size = self.logical_size(rect)?;
+        let target = self.placement(rect, native_size, &properties)?;
+
+        let saved = self.backend.attach(handle)?;
+        self.wallpapers.borrow_mut().insert(handle, Wallpaper { saved, native_size, properties });
+        // attached windows start out on top of the others
+        self.layers.borrow_mut().retain(|&window| window != handle);
+        self.layers.borrow_mut().push(handle);
+
+        self.move_to(handle, rect, target)
     }
 
-    (style, ex_style)
-}
+    pub fn add_window(&self,
+        command: Option<&mut Command>, selector: WindowSelector, properties: WallpaperProperties,
+        wait: &WaitPolicy
+    ) -> Result<WindowId, WallpaperError> {
 
-unsafe fn update_window_styles(wnd: HWND, and: i32, ex_and: i32, or: i32, ex_or: i32) -> bool {
-    use winapi::um::winuser::{SetWindowLongW, GWL_STYLE, GWL_EXSTYLE};
+        // compiled before spawning so a bad pattern doesn't leave an unwanted process behind
+        let matcher = Matcher::new(&selector)?;
 
-    let (mut style, mut ex_style) = get_window_style(wnd);
+        let process_id = match command {
+            Some(command) => self.backend.spawn(command).map_err(WallpaperError::SpawnFailed)?,
+            None => {
+                if let WindowSelector::None = selector {
+                    return Err(WallpaperError::NothingToSelect);
+                }
+                0
+            }
+        };
 
-    style &= and;
-    ex_style &= ex_and;
-    style |= or;
-    ex_style |= ex_or;
+        let mut tree = ProcessTree::new(process_id);
+        let handle = self.wait_for_window(wait, || match selector {
+            WindowSelector::None => self.find_spawned(&mut tree),
+            _ => self.find_matching(&matcher),
+        })?;
 
-    SetLastError(0);
-    let style = SetWindowLongW(wnd, GWL_STYLE, style);
-    let ex_style = SetWindowLongW(wnd, GWL_EXSTYLE, ex_style);
-    if (style == 0 || ex_style == 0) && GetLastError() != 0 {
-        panic!("SetWindowLongW failed, GetLastError says: '{}'", GetLastError());
+        self.add_window_by_handle(handle, properties)?;
+        Ok(handle)
     }
 
-    return true;
-}
+    /// Calls `find` until it returns a window acceptable under `wait`.
+    ///
+    /// Between lookups the backend waits for windows to change, so the delays of `wait` only
+    /// matter for backends without change notifications and for title stabilisation. Both the
+    /// lookups and the waits count towards the timeout.
+    fn wait_for_window(
+        &self, wait: &WaitPolicy, mut find: impl FnMut() -> Result<Option<WindowId>, WallpaperError>
+    ) -> Result<WindowId, WallpaperError> {
+        std::thread::sleep(Duration::from_millis(wait.initial_delay_ms));
+        let timeout = Duration::from_millis(wait.timeout_ms);
+        let stable_title = Duration::from_millis(wait.stable_title_ms);
+        let mut elapsed = Duration::from_millis(0);
+        let mut interval = wait.interval_ms as f64;
+        // window seen on the previous lookup, its title and when the title last changed
+        let mut candidate: Option<(WindowId, String, Duration)> = None;
 
-unsafe fn get_window_rect(wnd: HWND) -> Option<RECT> {
-    let rect: RECT = Default::default();
-    let failed = GetWindowRect(wnd, &rect as *const RECT as *mut RECT) == 0;
-    if failed {
-        eprintln!("GetWindowRect failed, GetLastError says: '{}'", GetLastError());
-        return None;
+        loop {
+            let lookup = Instant::now();
+            let found = find()?.filter(|&window| !wait.require_size || self.has_size(window));
+            elapsed += lookup.elapsed();
+            if let Some(window) = found {
+                let title = self.backend.window_title(window);
+                let since = match candidate {
+                    Some((previous, ref previous_title, since)) if previous == window && *previous_title == title => since,
+                    _ => elapsed,
+                };
+                if elapsed - since >= stable_title {
+                    return Ok(window);
+                }
+                candidate = Some((window, title, since));
+            } else {
+                candidate = None;
+            }
+
+            if elapsed >= timeout {
+                return Err(WallpaperError::SelectorTimedOut);
+            }
+            let delay = Duration::from_millis((interval.round() as u64).max(1)).min(timeout - elapsed);
+            elapsed += self.backend.wait_for_changes(delay);
+            interval = (interval * wait.backoff).min(wait.max_interval_ms as f64);
+        }
     }
-    return Some(rect);
-}
 
-unsafe fn map_window_rect(wallpaper: HWND, wnd: HWND) -> Option<RECT> {
-    if let Some(rect) = get_window_rect(wnd) {
-        MapWindowPoints(null_mut(), wallpaper, &rect as *const RECT as PPOINT, 2);
-        return Some(rect);
+    fn has_size(&self, window: WindowId) -> bool {
+        match self.backend.window_rect(window) {
+            Ok(rect) => rect.right > rect.left && rect.bottom > rect.top,
+            Err(_) => false,
+        }
     }
-    return None;
-}
 
-unsafe fn move_window(wnd: HWND, rect: RECT) -> bool {
-    let success = SetWindowPos(
-        wnd, null_mut(), rect.left, rect.top, rect.right - rect.left, rect.bottom - rect.top, 0
-    );
-    if success == 0 {
-        eprintln!("SetWindowPos failed, GetLastError says: '{}'", GetLastError());
-        return false;
+    /// Returns the first listed window matching `selector`.
+    pub fn find_window(&self, selector: &WindowSelector) -> Result<Option<WindowId>, WallpaperError> {
+        self.find_matching(&Matcher::new(selector)?)
     }
-    return true;
-}
 
-unsafe fn add_window_as_wallpaper(wallpaper: HWND, wnd: HWND) -> bool {
-    use winapi::um::winuser::{
-        SetParent,
-        WS_CHILD, WS_CAPTION, WS_THICKFRAME, WS_SYSMENU, WS_MAXIMIZEBOX, WS_MINIMIZEBOX,
-        WS_EX_DLGMODALFRAME, WS_EX_COMPOSITED, WS_EX_WINDOWEDGE, WS_EX_CLIENTEDGE, WS_EX_LAYERED, WS_EX_STATICEDGE, 
-        WS_EX_TOOLWINDOW, WS_EX_APPWINDOW,
-    };
+    /// Returns the first listed window owned by a process of `tree`. Listed windows are visible,
+    /// titled and not tool windows, which skips splash screens and message-only windows.
+    fn find_spawned(&self, tree: &mut ProcessTree) -> Result<Option<WindowId>, WallpaperError> {
+        let windows = self.backend.list_windows()?;
+        let parents = self.backend.process_parents();
+        Ok(windows.into_iter().find(|&window| tree.contains(self.backend.window_pid(window), &parents)))
+    }
 
-    let wnd_class = {
-        let wnd_class: &mut [u16] = &mut [0; 512];
-        GetClassNameW(wnd, wnd_class.as_mut_ptr(), wnd_class.len() as i32 - 1);
-        OsString::from_wide(&wnd_class[..wnd_class.iter().position(|&c| c == 0).unwrap()])
-    };
+    fn find_matching(&self, matcher: &Matcher) -> Result<Option<WindowId>, WallpaperError> {
+        let windows = self.backend.list_windows()?;
+        Ok(windows.into_iter().find(|&window| matcher.matches(&self.backend, window)))
+    }
 
-    if wallpaper == wnd || wnd_class == "Shell_TrayWnd" {
-        eprintln!("can't add this window");
-        return false;
+    /// Moves and resizes a wallpaper, `rect` is in screen coordinates. From then on it stays at
+    /// `rect` relative to the monitor it is on.
+    pub fn move_wallpaper(&self, handle: WindowId, rect: Rect) -> Result<(), WallpaperError> {
+        self.check_attached(handle)?;
+        self.backend.move_window(handle, self.backend.map_to_layer(rect))?;
+        self.remember(handle, WallpaperProperties::default());
+        Ok(())
     }
 
-    let is_child = IsChild(wallpaper, wnd) != 0;
-    if is_child {
-        eprintln!("already added");
-        return false;
+    /// Makes a wallpaper cover `monitor`, or the monitor it is on.
+    pub fn make_fullscreen(
+        &self, handle: WindowId, monitor: Option<&MonitorSelector>, scaling: Scaling
+    ) -> Result<(), WallpaperError> {
+        self.check_attached(handle)?;
+        let properties = WallpaperProperties {
+            fullscreen: true, monitor: monitor.cloned(), scaling, ..WallpaperProperties::default()
+        };
+        self.reposition(handle, properties)
     }
 
-    /*
-     * styles blacklist taken from https://github.com/Codeusa/Borderless-Gaming/
-     * blob/2fef4ccc121412f215cd7f185c4351fd634cab8b/BorderlessGaming.Logic/
-     * Windows/Manipulation.cs#L70
-     */
+    /// Makes a wallpaper cover all monitors.
+    pub fn span_monitors(&self, handle: WindowId, scaling: Scaling) -> Result<(), WallpaperError> {
+        self.check_attached(handle)?;
+        self.reposition(handle, WallpaperProperties { span: true, scaling, ..WallpaperProperties::default() })
+    }
 
-    /* TODO: somehow save old styles so we can restore them */
+    /// Moves an attached window to where `properties` put it.
+    fn reposition(&self, handle: WindowId, properties: WallpaperProperties) -> Result<(), WallpaperError> {
+        let rect = self.backend.window_rect(handle)?;
+        // windows attached by someone else are taken as they are
+        let native_size = match self.wallpapers.borrow().get(&handle) {
+            Some(wallpaper) => wallpaper.native_size,
+            None => self.logical_size(rect)?,
+        };
+        self.move_to(handle, rect, self.placement(rect, native_size, &properties)?)?;
+        self.remember(handle, properties);
+        Ok(())
+    }
 
-    let and: i32 = !(
-        WS_CAPTION |
-        WS_THICKFRAME |
-        WS_SYSMENU |
-        WS_MAXIMIZEBOX |
-        WS_MINIMIZEBOX
-    ) as i32;
+    /// Moves a wallpaper one layer up, over the wallpaper right above it.
+    pub fn raise(&self, handle: WindowId) -> Result<(), WallpaperError> {
+        self.restack(handle, |layer| layer + 1)
+    }
 
-    let ex_and: i32 = !(
-        WS_EX_DLGMODALFRAME |
-        WS_EX_COMPOSITED |
-        WS_EX_WINDOWEDGE |
-        WS_EX_CLIENTEDGE |
-        WS_EX_LAYERED |
-        WS_EX_STATICEDGE |
-        WS_EX_TOOLWINDOW |
-        WS_EX_APPWINDOW
-    ) as i32;
+    /// Moves a wallpaper one layer down, under the wallpaper right below it.
+    pub fn lower(&self, handle: WindowId) -> Result<(), WallpaperError> {
+        self.restack(handle, |layer| layer.saturating_sub(1))
+    }
 
-    if !update_window_styles(wnd, and, ex_and, WS_CHILD as i32, 0) {
-        return false;
+    /// Moves a wallpaper to `layer`, counted from the bottom starting at 0, or to the top if
+    /// there are fewer layers.
+    pub fn set_layer(&self, handle: WindowId, layer: usize) -> Result<(), WallpaperError> {
+        self.restack(handle, |_| layer)
     }
 
-    /* window retains screen coordinates so we need to adjust them */
-    map_window_rect(wallpaper, wnd).unwrap(); 
+    /// Moves a wallpaper from its layer to the one returned by `to`.
+    fn restack(&self, handle: WindowId, to: impl FnOnce(usize) -> usize) -> Result<(), WallpaperError> {
+        self.check_attached(handle)?;
+        let mut layers = self.stacking()?;
+        let current = layers.iter().position(|&window| window == handle).ok_or(WallpaperError::NotAttached)?;
+        layers.remove(current);
+        let layer = to(current).min(layers.len());
+        if layer == current {
+            return Ok(());
+        }
+        layers.insert(layer, handle);
 
-    let prev_parent = SetParent(wnd, wallpaper);
-    if prev_parent.is_null() {
-        panic!("SetParent failed, GetLastError says: '{}'", GetLastError());
+        self.backend.restack(&layers)?;
+        *self.layers.borrow_mut() = layers;
+        Ok(())
     }
-    ShowWindow(wnd, SW_SHOW);
-
-    return true;
-}
 
-unsafe fn remove_window_from_wallpaper(wallpaper: HWND, wnd: HWND) -> bool {
-    use winapi::um::winuser::{
-        SetParent, GetDesktopWindow, InvalidateRect,
-        WS_EX_APPWINDOW, WS_OVERLAPPEDWINDOW, 
-        SWP_FRAMECHANGED, SWP_NOMOVE, SWP_NOSIZE, SWP_NOZORDER, SWP_NOOWNERZORDER
-    };
+    /// Keeps `properties` for when the displays change, if the engine attached `handle`.
+    fn remember(&self, handle: WindowId, properties: WallpaperProperties) {
+        if let Some(wallpaper) = self.wallpapers.borrow_mut().get_mut(&handle) {
+            wallpaper.properties = properties;
+        }
+    }
 
-    if SetParent(wnd, GetDesktopWindow()).is_null() {
-        eprintln!("SetParent failed, GetLastError says: '{}'", GetLastError());
-        return false;
+    /// Size of a window at `rect` in logical pixels of the monitor it is on.
+    fn logical_size(&self, rect: Rect) -> Result<(i32, i32), WallpaperError> {
+        let dpi = self.backend.monitor_at(rect.left, rect.top)?.dpi;
+        Ok(geometry::rescale_size((rect.width(), rect.height()), dpi, DEFAULT_DPI))
     }
 
-    let or = WS_OVERLAPPEDWINDOW as i32;
-    let ex_or = WS_EX_APPWINDOW as i32;
+    pub fn list_monitors(&self) -> Result<Vec<Monitor>, WallpaperError> {
+        self.backend.monitors()
+    }
 
-    if !update_window_styles(wnd, -1, -1, or, ex_or) {
-        return false;
+    /// Looks up a monitor by index or by name; names are compared ignoring case.
+    pub fn find_monitor(&self, selector: &MonitorSelector) -> Result<Monitor, WallpaperError> {
+        let mut monitors = self.backend.monitors()?;
+        selector.position(&monitors).map(|index| monitors.swap_remove(index)).ok_or(WallpaperError::MonitorNotFound)
     }
 
-    SetWindowPos(
-        wnd, null_mut(), 0, 0, 0, 0, 
-        SWP_FRAMECHANGED | SWP_NOMOVE | SWP_NOSIZE | SWP_NOZORDER | SWP_NOOWNERZORDER
-    );
+    /// Takes `handle` off the desktop and restores it to a normal window, leaving the application running.
+    pub fn detach_wallpaper(&self, handle: WindowId) -> Result<(), WallpaperError> {
+        self.check_attached(handle)?;
+        self.detach(handle)?;
+        self.backend.redraw();
+        Ok(())
+    }
 
-    InvalidateRect(wallpaper, null_mut(), 1);
-    // wp_id(); /* can sometimes fix leftover unrefreshed portions */
+    /// Takes `handle` off the desktop and closes it.
+    pub fn remove_wallpaper(&self, handle: WindowId) -> Result<(), WallpaperError> {
+        self.check_attached(handle)?;
 
-    true
-}
+        match self.detach(handle) {
+            // some backends can't give windows back, which doesn't matter as we close it anyway
+            Err(WallpaperError::Unsupported) => {},
+            result => result?,
+        }
+        std::thread::sleep(std::time::Duration::from_millis(32));
+        self.backend.close_window(handle);
+        std::thread::sleep(std::time::Duration::from_millis(32));
+        self.backend.redraw();
+        Ok(())
+    }
 
-unsafe fn set_fullscreen(wallpaper: HWND, wnd: HWND) -> bool {
-    if let Some(current_rect) = get_window_rect(wnd) {
-        let monitor = MonitorFromPoint(POINT {x: current_rect.left, y: current_rect.top}, MONITOR_DEFAULTTONEAREST);
-        if monitor.is_null() {
-            eprintln!("MonitorFromWindow failed, GetLastError says: '{}'", GetLastError());
-            return false;
+    /// Gives every window attached by this engine back its original state. Meant to be called
+    /// before exiting; windows which can't be restored are skipped, and the first error is returned.
+    pub fn restore_all(&self) -> Result<(), WallpaperError> {
+        // windows left in a lost layer can only be found once they are in the new one; when the
+        // layer can't be recreated, the windows still in it are restored all the same
+        let _ = self.recover_layer();
+        let handles: Vec<WindowId> = self.wallpapers.borrow().keys().cloned().collect();
+
+        let mut result = Ok(());
+        for handle in handles {
+            if !self.backend.window_exists(handle) || !self.backend.is_attached(handle) {
+                self.wallpapers.borrow_mut().remove(&handle);
+                continue;
+            }
+            match self.detach(handle) {
+                Ok(()) | Err(WallpaperError::Unsupported) => {},
+                Err(error) => if result.is_ok() {
+                    result = Err(error);
+                },
+            }
         }
 
-        let mut mi: MONITORINFO = Default::default();
-        mi.cbSize = std::mem::size_of::<MONITORINFO>() as u32;
-        let success = GetMonitorInfoW(monitor, &mi as *const MONITORINFO as *mut MONITORINFO);
-        if success == 0 {
-            eprintln!("GetMonitorInfoW failed, GetLastError says: '{}'", GetLastError());
-            return false;
+        self.backend.redraw();
+        result
+    }
+
+    /// Finds or recreates the wallpaper layer if it was lost, e.g. because explorer.exe restarted,
+    /// and attaches wallpapers which outlived it to the new one. Every operation on wallpapers
+    /// does this first; a `WorkerLost` event tells which wallpapers were destroyed meanwhile.
+    pub fn recover_layer(&self) -> Result<(), WallpaperError> {
+        if self.backend.is_layer_valid() {
+            return Ok(());
+        }
+        self.backend.reacquire_layer()?;
+
+        // attaching puts windows on top, so attaching them again bottom first keeps their order
+        let layers = self.layers.borrow().clone();
+        let mut handles: Vec<WindowId> = self.wallpapers.borrow().keys().cloned().collect();
+        handles.sort_by_key(|handle| (layers.iter().position(|window| window == handle), *handle));
+
+        let mut destroyed = Vec::new();
+        for handle in handles {
+            if !self.backend.window_exists(handle) {
+                self.wallpapers.borrow_mut().remove(&handle);
+                destroyed.push(handle);
+            } else if !self.backend.is_attached(handle) {
+                // the state saved on the first attach is still what the window should get back
+                self.backend.attach(handle)?;
+            }
         }
-    
-        MapWindowPoints(null_mut(), wallpaper, &mi.rcMonitor as *const RECT as PPOINT, 2);
+        self.events.borrow_mut().push(EngineEvent::WorkerLost { destroyed });
 
-        move_window(wnd, mi.rcMonitor);
-        
-        return true;
+        self.backend.redraw();
+        Ok(())
     }
 
-    return false;
-}
+    /// Returns the events which happened since the last call.
+    pub fn take_events(&self) -> Vec<EngineEvent> {
+        self.events.borrow_mut().drain(..).collect()
+    }
 
-unsafe fn list_immediate_children(parent: HWND) -> Vec<HWND> {
-    use winapi::um::winuser::EnumChildWindows;
+    fn check_attached(&self, handle: WindowId) -> Result<(), WallpaperError> {
+        if !self.backend.window_exists(handle) {
+            return Err(WallpaperError::WindowNotFound);
+        }
+        self.recover_layer()?;
+        self.check_displays()?;
+        if !self.backend.is_attached(handle) {
+            return Err(WallpaperError::NotAttached);
+        }
+        Ok(())
+    }
 
-    #[repr(C)]
-    struct WindowState {
-        parent: HWND,
-        handles: Vec<HWND>,
+    fn detach(&self, handle: WindowId) -> Result<(), WallpaperError> {
+        let saved = self.wallpapers.borrow_mut().remove(&handle).map(|wallpaper| wallpaper.saved);
+        self.layers.borrow_mut().retain(|&window| window != handle);
+        self.backend.detach(handle, saved)
     }
-    
-    let mut s = WindowState { parent, handles: Vec::new() };
 
-    extern "system" fn enum_windows(wnd: HWND, lp: LPARAM) -> i32 {
-        use winapi::um::winuser::{GetAncestor, GA_PARENT};
+    /// Where `properties` put a window which is at `rect` now, both in screen coordinates.
+    /// `native_size` is the logical size of the window before it was attached, which `Scaling`
+    /// keeps and scales by the DPI of the target monitor.
+    ///
+    /// A spanning window covers all monitors. Otherwise a window with a `Placement` is put there
+    /// on the chosen monitor, a fullscreen window covers the chosen monitor, and other windows
+    /// are moved there keeping their size and their offset from the corner of the monitor they
+    /// are on now. Without a chosen monitor it is the current one.
+    fn placement(
+        &self, rect: Rect, native_size: (i32, i32), properties: &WallpaperProperties
+    ) -> Result<Rect, WallpaperError> {
+        if properties.span {
+            return span(&self.backend.monitors()?, native_size, properties.scaling);
+        }
+
+        let current = self.backend.monitor_at(rect.left, rect.top)?;
+        let monitor = match &properties.monitor {
+            Some(monitor) => self.find_monitor(monitor)?,
+            None => current.clone(),
+        };
+        Ok(place_on(rect, native_size, properties, &current, &monitor))
+    }
 
-        let s: *mut WindowState = lp as *mut WindowState;
-        
-        unsafe {
-            if GetAncestor(wnd, GA_PARENT) == (*s).parent {
-                (*s).handles.push(wnd);
+    /// Places every wallpaper attached by this engine again if monitors were added, removed or
+    /// changed since the last call, and adds a `DisplaysChanged` event if any of them moved. Like
+    /// `recover_layer`, every operation on wallpapers does this first.
+    ///
+    /// Wallpapers follow the monitor chosen in their properties, or else the monitor they were on.
+    /// When that monitor is gone they move to the primary one.
+    pub fn check_displays(&self) -> Result<(), WallpaperError> {
+        if !self.backend.displays_changed() {
+            return Ok(());
+        }
+        let monitors = self.backend.monitors()?;
+        // with every monitor switched off for a moment there is nowhere to go, so wait for them
+        let fallback = match monitors.iter().find(|monitor| monitor.primary).or_else(|| monitors.first()) {
+            Some(fallback) => fallback,
+            None => return Ok(()),
+        };
+        if *self.monitors.borrow() == monitors {
+            return Ok(());
+        }
+        let previous = self.monitors.replace(monitors.clone());
+        self.backend.resize_layer()?;
+        // the layer covers all monitors, and the windows in it moved along with its corner
+        let shift = match (geometry::virtual_screen(&previous), geometry::virtual_screen(&monitors)) {
+            (Some(before), Some(after)) => (before.left - after.left, before.top - after.top),
+            _ => (0, 0),
+        };
+
+        let mut handles: Vec<WindowId> = self.wallpapers.borrow().keys().cloned().collect();
+        handles.sort();
+
+        let mut moved = Vec::new();
+        for handle in handles {
+            if !self.backend.window_exists(handle) || !self.backend.is_attached(handle) {
+                continue;
+            }
+            let (native_size, properties) = match self.wallpapers.borrow().get(&handle) {
+                Some(wallpaper) => (wallpaper.native_size, wallpaper.properties.clone()),
+                None => continue,
+            };
+            let placed = self.backend.window_rect(handle).and_then(|rect| {
+                let target = if properties.span {
+                    span(&monitors, native_size, properties.scaling)?
+                } else {
+                    let before = rect.offset(shift.0, shift.1);
+                    let current = geometry::nearest_monitor(&previous, before.left, before.top)
+                        .unwrap_or_else(|| fallback.clone());
+                    let monitor = match &properties.monitor {
+                        Some(selector) => selector.position(&monitors).map(|index| &monitors[index]),
+                        None => monitors.iter().find(|monitor| monitor.name == current.name),
+                    };
+                    place_on(before, native_size, &properties, &current, monitor.unwrap_or(fallback))
+                };
+                self.move_to(handle, rect, target).map(|()| target != rect)
+            });
+            // a window which can't be moved, e.g. because it just closed, doesn't hold up the others
+            if let Ok(true) = placed {
+                moved.push(handle);
             }
         }
-        
-        return 1;
+        if !moved.is_empty() {
+            self.events.borrow_mut().push(EngineEvent::DisplaysChanged { moved });
+        }
+
+        self.backend.redraw();
+        Ok(())
     }
 
-    SetLastError(0);
-    EnumChildWindows(parent, Some(enum_windows), &mut s as *mut WindowState as LPARAM);
-    if GetLastError() != 0 {
-        panic!("EnumChildWindows failed, GetLastError says: {}", GetLastError());
+    /// Moves an attached window from `rect` to `target`, both in screen coordinates.
+    fn move_to(&self, handle: WindowId, rect: Rect, target: Rect) -> Result<(), WallpaperError> {
+        if target == rect {
+            return Ok(());
+        }
+        self.backend.move_window(handle, self.backend.map_to_layer(target))
     }
 
-    s.handles.sort_unstable();
+}
 
-    return s.handles;
+/// Rectangle of a window with `native_size` spanning all `monitors`.
+fn span(monitors: &[Monitor], native_size: (i32, i32), scaling: Scaling) -> Result<Rect, WallpaperError> {
+    let screen = geometry::virtual_screen(monitors).ok_or(WallpaperError::MonitorNotFound)?;
+    // a window spanning monitors with different scale factors is sized for the primary one
+    let dpi = monitors.iter().find(|monitor| monitor.primary).map_or(DEFAULT_DPI, |monitor| monitor.dpi);
+    let (width, height) = geometry::rescale_size(native_size, DEFAULT_DPI, dpi);
+    Ok(scaling.apply(width, height, screen))
+}
+
+/// Rectangle of a window at `rect` on the `current` monitor put on `monitor` by `properties`,
+/// apart from spanning.
+fn place_on(
+    rect: Rect, native_size: (i32, i32), properties: &WallpaperProperties, current: &Monitor, monitor: &Monitor
+) -> Rect {
+    let area = if properties.work_area { monitor.work_area } else { monitor.rect };
+    if let Some(placement) = &properties.placement {
+        placement.resolve(area, monitor.dpi)
+    } else if properties.fullscreen {
+        let (width, height) = geometry::rescale_size(native_size, DEFAULT_DPI, monitor.dpi);
+        properties.scaling.apply(width, height, area)
+    } else {
+        geometry::move_between(rect, current, monitor)
+    }
 }
 
-unsafe fn find_window_by_pid(pid: u32) -> HWND {
-    use winapi::um::winuser::{EnumWindows, GetWindowThreadProcessId};
-    use winapi::shared::minwindef::{DWORD, LPDWORD};
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use crate::backend::{DesktopBackend, Monitor, Rect};
+    use crate::backend::fake::{FakeDesktop, FakeWindow};
+    use crate::geometry::DEFAULT_DPI;
+    use super::{
+        Anchor, Engine, EngineEvent, Margins, MonitorSelector, Placement, Scaling, WaitPolicy, WallpaperError,
+        WallpaperProperties, WindowSelector,
+    };
+
+    const FULLSCREEN: WallpaperProperties = WallpaperProperties {
+        fullscreen: true, monitor: None, span: false, placement: None, work_area: false, scaling: Scaling::Stretch,
+    };
+    const WINDOWED: WallpaperProperties = WallpaperProperties {
+        fullscreen: false, monitor: None, span: false, placement: None, work_area: false, scaling: Scaling::Stretch,
+    };
 
-    #[repr(C)]
-    #[derive(Debug)]
-    struct Data {
-        handle: HWND,
-        pid: u32,
+    /// Policy which looks for the window exactly `count` times.
+    fn attempts(count: u64) -> WaitPolicy {
+        WaitPolicy { timeout_ms: count - 1, interval_ms: 1, backoff: 1.0, ..WaitPolicy::default() }
     }
 
-    extern "system" fn enum_windows(wnd: HWND, data: LPARAM) -> i32 {
-        let mut data = data as *mut Data;
+    fn rect(left: i32, top: i32, right: i32, bottom: i32) -> Rect {
+        Rect { left, top, right, bottom }
+    }
 
-        unsafe {
-            let mut this_pid: DWORD = 0;
+    fn monitor(name: &str, primary: bool, rect: Rect) -> Monitor {
+        Monitor { name: name.to_owned(), primary, rect, work_area: rect, dpi: DEFAULT_DPI }
+    }
 
-            GetWindowThreadProcessId(wnd, &mut this_pid as LPDWORD);
+    /// Primary 1920x1080 monitor with a 1280x1024 one to the left of it.
+    fn two_monitors() -> FakeDesktop {
+        FakeDesktop::with_monitors(vec![
+            monitor("DISPLAY1", true, rect(0, 0, 1920, 1080)),
+            monitor("DISPLAY2", false, rect(-1280, 0, 0, 1024)),
+        ])
+    }
 
-            if this_pid == (*data).pid {
-                (*data).handle = wnd;
-                return 0;
-            }
-        }
+    #[test]
+    fn add_by_handle_attaches_window() {
+        let engine = Engine::new(FakeDesktop::new());
+        let window = engine.backend().add(FakeWindow::new("clock", "Clock", 42));
 
-        return 1;
+        engine.add_window_by_handle(window, WINDOWED).unwrap();
+
+        let state = engine.backend().window(window);
+        assert_eq!(state.parent, Some(engine.backend().layer()));
+        assert!(!state.decorated);
+        assert_eq!(state.rect, rect(100, 100, 740, 580));
+        assert_eq!(engine.list_active().unwrap(), [window]);
     }
 
-    let mut data = Data {handle: null_mut(), pid};
-    
-    SetLastError(0);
-    EnumWindows(Some(enum_windows), &mut data as *mut Data as LPARAM);
-    if GetLastError() != 0 {
-        panic!("EnumWindows failed, GetLastError says: {}", GetLastError());
+    #[test]
+    fn add_by_handle_covers_monitor_when_fullscreen() {
+        let engine = Engine::new(FakeDesktop::new());
+        let window = engine.backend().add(FakeWindow::new("video.mp4 - mpv", "mpv", 42));
+
+        engine.add_window_by_handle(window, FULLSCREEN).unwrap();
+
+        assert_eq!(engine.backend().window(window).rect, rect(0, 0, 1920, 1080));
     }
-    
-    data.handle
-}
 
-pub fn list_windows() -> Vec<HWND> {
-    use winapi::um::winuser::{
-        EnumWindows, IsWindowVisible, GetLastActivePopup, GetAncestor, GetWindowTextLengthW, 
-        GA_ROOTOWNER, WS_EX_NOREDIRECTIONBITMAP, WS_EX_TOOLWINDOW
-    };
+    #[test]
+    fn add_by_handle_uses_monitor_of_window() {
+        let left = rect(-1280, 0, 0, 1024);
+        let engine = Engine::new(two_monitors());
+        let window = engine.backend().add(
+            FakeWindow::new("video.mp4 - mpv", "mpv", 42).at(rect(-1000, 100, -200, 700))
+        );
 
-    // https://stackoverflow.com/questions/210504/enumerate-windows-like-alt-tab-does
-    unsafe fn should_list(hwnd: HWND) -> bool {
-        // Start at the root owner
-        let mut hwnd_walk = GetAncestor(hwnd, GA_ROOTOWNER);
-        // See if we are the last active visible popup
-        let mut hwnd_try = null_mut();
-        loop {
-            let hwnd_try_next = GetLastActivePopup(hwnd_walk);
-            if hwnd_try_next == hwnd_try || IsWindowVisible(hwnd_try_next) == 1 {
-                break;
-            }
-            hwnd_try = hwnd_try_next;
-            hwnd_walk = hwnd_try;
-        }
+        engine.add_window_by_handle(window, FULLSCREEN).unwrap();
 
-        return hwnd_walk == hwnd;
+        assert_eq!(engine.backend().window(window).rect, left);
     }
 
-    extern "system" fn list_windows_callback(hwnd: HWND, lp: LPARAM) -> i32 {
-        let data = lp as *mut Vec<HWND>;
+    #[test]
+    fn add_by_handle_rejects_forbidden_windows() {
+        let engine = Engine::new(FakeDesktop::new());
+        let taskbar = engine.backend().add(FakeWindow::new("", "Shell_TrayWnd", 1));
 
-        unsafe {
-            if  IsWindowVisible(hwnd) == 1 && GetWindowTextLengthW(hwnd) > 0 && should_list(hwnd) {
-                let (_, ex_style) = get_window_style(hwnd);
-                if (ex_style as u32 & WS_EX_NOREDIRECTIONBITMAP) == 0 && (ex_style as u32 & WS_EX_TOOLWINDOW) == 0 {
-                    (*data).push(hwnd);
-                }
-            }
+        match engine.add_window_by_handle(taskbar, WINDOWED) {
+            Err(WallpaperError::ForbiddenWindow(class)) => assert_eq!(class, "Shell_TrayWnd"),
+            result => panic!("Unexpected result: {:?}", result),
         }
+        match engine.add_window_by_handle(engine.backend().layer(), WINDOWED) {
+            Err(WallpaperError::ForbiddenWindow(class)) => assert_eq!(class, "WorkerW"),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert!(engine.list_active().unwrap().is_empty());
+    }
 
-        1
+    #[test]
+    fn add_by_handle_rejects_attached_windows() {
+        let engine = Engine::new(FakeDesktop::new());
+        let window = engine.backend().add(FakeWindow::new("clock", "Clock", 42));
+
+        engine.add_window_by_handle(window, WINDOWED).unwrap();
+        match engine.add_window_by_handle(window, WINDOWED) {
+            Err(WallpaperError::AlreadyAttached) => {},
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert_eq!(engine.list_active().unwrap(), [window]);
     }
 
-    let mut data: Vec<HWND> = Vec::new();
+    #[test]
+    fn add_requires_command_or_selector() {
+        let engine = Engine::new(FakeDesktop::new());
 
-    unsafe {
-        SetLastError(0);
-        EnumWindows(Some(list_windows_callback), &mut data as *mut Vec<HWND> as LPARAM);
-        if GetLastError() != 0 {
-            panic!("EnumWindows failed, GetLastError says: '{}'", GetLastError());
+        match engine.add_window(None, WindowSelector::None, WINDOWED, &attempts(10)) {
+            Err(WallpaperError::NothingToSelect) => {},
+            result => panic!("Unexpected result: {:?}", result),
         }
+        assert_eq!(engine.backend().polls(), 0);
     }
 
-    data
-}
+    #[test]
+    fn add_finds_existing_window_by_title() {
+        let engine = Engine::new(FakeDesktop::new());
+        engine.backend().add(FakeWindow::new("notes", "Notepad", 41));
+        let window = engine.backend().add(FakeWindow::new("clock", "Clock", 42));
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub enum WindowSelector<'a> {
-    WindowTitle(&'a str),
-    None,
-}
+        let added = engine.add_window(None, WindowSelector::WindowTitle("clock".to_owned()), WINDOWED, &attempts(10));
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct WallpaperProperties {
-    pub fullscreen: bool
-}
+        assert_eq!(added.unwrap(), window);
 
-#[derive(Debug)]
-pub enum EngineError {
-    ProgmanNotFound,
-    UnableToSpawnWorker,
-}
+        assert_eq!(engine.list_active().unwrap(), [window]);
+        assert!(engine.backend().spawned().is_empty());
+    }
 
-#[derive(Debug)]
-pub struct Engine {
-    progman: HWND,
-    worker: HWND,
-}
+    #[test]
+    fn add_finds_existing_window_by_class() {
+        let engine = Engine::new(FakeDesktop::new());
+        engine.backend().add(FakeWindow::new("notes", "Notepad", 41));
+        let window = engine.backend().add(FakeWindow::new("video.mp4 - mpv", "mpv", 42));
 
-impl Engine {
+        let added = engine.add_window(None, WindowSelector::ClassName("mpv".to_owned()), WINDOWED, &attempts(10));
 
-    pub fn new() -> Result<Engine, EngineError> {
-        let progman_handle = find_window_by_class("Progman");
-        if progman_handle.is_null() {
-            return Err(EngineError::ProgmanNotFound);
-        }
+        assert_eq!(added.unwrap(), window);
+    }
 
-        let worker_handle = unsafe { find_or_spawn_worker(progman_handle) };
-        if worker_handle.is_null() {
-            return Err(EngineError::UnableToSpawnWorker);
+    #[test]
+    fn add_rejects_invalid_pattern_before_spawning() {
+        let engine = Engine::new(FakeDesktop::new());
+
+        let mut command = Command::new("mpv");
+        let selector = WindowSelector::TitleRegex { pattern: "[mpv".to_owned(), case_sensitive: false };
+        match engine.add_window(Some(&mut command), selector, WINDOWED, &attempts(10)) {
+            Err(WallpaperError::InvalidPattern(_)) => {},
+            result => panic!("Unexpected result: {:?}", result),
         }
-        
-        Ok(Engine {progman: progman_handle, worker: worker_handle})
+        assert!(engine.backend().spawned().is_empty());
     }
 
-    pub fn list_active(&self) -> Vec<HWND> {
-        unsafe {
-            // TODO this is not safe until we add a check for worker validity here.
-            list_immediate_children(self.worker)
+    #[test]
+    fn add_ignores_hidden_windows_when_selecting_by_title() {
+        let engine = Engine::new(FakeDesktop::new());
+        engine.backend().add(FakeWindow::new("clock", "Clock", 42).hidden());
+
+        match engine.add_window(None, WindowSelector::WindowTitle("clock".to_owned()), WINDOWED, &attempts(3)) {
+            Err(WallpaperError::SelectorTimedOut) => {},
+            result => panic!("Unexpected result: {:?}", result),
         }
+        assert!(engine.list_active().unwrap().is_empty());
+    }
+
+    #[test]
+    fn add_waits_for_spawned_window_by_title() {
+        let engine = Engine::new(FakeDesktop::new());
+        engine.backend().on_spawn(vec![FakeWindow::new("clock", "Clock", 0)], 4);
+
+        let mut command = Command::new("clock.exe");
+        engine.add_window(Some(&mut command), WindowSelector::WindowTitle("clock".to_owned()), WINDOWED, &attempts(10)).unwrap();
+
+        assert_eq!(engine.backend().spawned(), ["clock.exe"]);
+        assert_eq!(engine.backend().polls(), 5);
+        assert_eq!(engine.list_active().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn add_waits_for_spawned_window_by_pid() {
+        let engine = Engine::new(FakeDesktop::new());
+        engine.backend().add(FakeWindow::new("other", "Other", 999));
+        engine.backend().on_spawn(vec![FakeWindow::new("player", "mpv", 0)], 2);
+
+        let mut command = Command::new("mpv");
+        let window = engine.add_window(Some(&mut command), WindowSelector::None, FULLSCREEN, &attempts(10)).unwrap();
+
+        assert_eq!(engine.list_active().unwrap(), [window]);
+        assert_eq!(engine.backend().window_title(window), "player");
+        assert_eq!(engine.backend().window(window).rect, rect(0, 0, 1920, 1080));
+        assert_eq!(engine.backend().polls(), 3);
+    }
+
+    #[test]
+    fn add_finds_window_of_forked_child() {
+        let engine = Engine::new(FakeDesktop::new());
+        engine.backend().add(FakeWindow::new("other", "Other", 999));
+        engine.backend().on_spawn(vec![
+            FakeWindow::new("", "SplashScreen", 0),
+            FakeWindow::new("message window", "MessageWindow", 0).hidden(),
+            FakeWindow::new("Steam", "SDL_app", 2001),
+        ], 1);
+
+        let mut command = Command::new("steam");
+        let window = engine.add_window(Some(&mut command), WindowSelector::None, WINDOWED, &attempts(10)).unwrap();
+
+        assert_eq!(engine.backend().window_title(window), "Steam");
+    }
+
+    #[test]
+    fn add_follows_process_tree_after_launcher_exits() {
+        let engine = Engine::new(FakeDesktop::new());
+        // cmd /c start: 2001 started the app 2002 and exited, 2002 is still known to be its child
+        engine.backend().on_spawn(vec![FakeWindow::new("app", "App", 2002)], 0);
+        engine.backend().set_parent_process(2002, 2001);
+        engine.backend().set_parent_process(2001, 1000);
+
+        let mut command = Command::new("cmd");
+        let window = engine.add_window(Some(&mut command), WindowSelector::None, WINDOWED, &attempts(10)).unwrap();
+
+        assert_eq!(engine.backend().window_title(window), "app");
     }
 
-    pub fn add_window_by_handle(&self, handle: HWND, properties: WallpaperProperties) -> bool {
-        if !unsafe { add_window_as_wallpaper(self.worker, handle) } {
-            eprintln!("Cannot add window to wallpaper");
-            return false;
+    #[test]
+    fn add_skips_windows_without_size() {
+        let engine = Engine::new(FakeDesktop::new());
+        engine.backend().add(FakeWindow::new("clock", "Clock", 41).at(rect(0, 0, 0, 0)));
+
+        match engine.add_window(None, WindowSelector::WindowTitle("clock".to_owned()), WINDOWED, &attempts(2)) {
+            Err(WallpaperError::SelectorTimedOut) => {},
+            result => panic!("Unexpected result: {:?}", result),
         }
 
-        if properties.fullscreen && !unsafe { set_fullscreen(self.worker, handle) } {
-            return false
+        let any_size = WaitPolicy { require_size: false, ..attempts(2) };
+        engine.add_window(None, WindowSelector::WindowTitle("clock".to_owned()), WINDOWED, &any_size).unwrap();
+    }
+
+    #[test]
+    fn add_waits_for_title_to_settle() {
+        let engine = Engine::new(FakeDesktop::new());
+        let window = engine.backend().add(FakeWindow::new("video.mp4 - mpv", "mpv", 41));
+
+        let settled = WaitPolicy { stable_title_ms: 3, ..attempts(10) };
+        let added = engine.add_window(None, WindowSelector::ClassName("mpv".to_owned()), WINDOWED, &settled);
+
+        assert_eq!(added.unwrap(), window);
+        assert_eq!(engine.backend().polls(), 4);
+    }
+
+    #[test]
+    fn add_gives_up_after_all_attempts() {
+        let engine = Engine::new(FakeDesktop::new());
+        engine.backend().on_spawn(vec![FakeWindow::new("slow", "Slow", 0)], 10);
+
+        let mut command = Command::new("slow");
+        match engine.add_window(Some(&mut command), WindowSelector::None, WINDOWED, &attempts(5)) {
+            Err(WallpaperError::SelectorTimedOut) => {},
+            result => panic!("Unexpected result: {:?}", result),
         }
 
-        true
+        assert_eq!(engine.backend().polls(), 5);
+        assert!(engine.list_active().unwrap().is_empty());
     }
-    
-    pub fn add_window(&self, 
-        command: Option<&mut Command>, selector: WindowSelector, properties: WallpaperProperties, 
-        wait_for: u64, attempts: u64
-    ) -> bool {
 
-        let process_id = match command {
-            Some(command) => command.spawn().expect("command failed to start").id(),
-            None => {
-                if let WindowSelector::None = selector {
-                    eprintln!("One or both of selector and command should be specified");
-                    return false;
-                }
-                0
-            }
+    #[test]
+    fn list_active_ignores_regular_windows() {
+        let engine = Engine::new(FakeDesktop::new());
+        let first = engine.backend().add(FakeWindow::new("first", "First", 1));
+        engine.backend().add(FakeWindow::new("second", "Second", 2));
+        let third = engine.backend().add(FakeWindow::new("third", "Third", 3));
+
+        engine.add_window_by_handle(third, WINDOWED).unwrap();
+        engine.add_window_by_handle(first, WINDOWED).unwrap();
+
+        assert_eq!(engine.list_active().unwrap(), [third, first]);
+    }
+
+    #[test]
+    fn remove_detaches_and_closes_window() {
+        let engine = Engine::new(FakeDesktop::new());
+        let window = engine.backend().add(FakeWindow::new("clock", "Clock", 42));
+        engine.add_window_by_handle(window, WINDOWED).unwrap();
+
+        engine.remove_wallpaper(window).unwrap();
+
+        assert!(engine.list_active().unwrap().is_empty());
+        assert!(!engine.backend().exists(window));
+        assert_eq!(engine.backend().closed(), [window]);
+    }
+
+    #[test]
+    fn move_places_wallpaper_in_screen_coordinates() {
+        let left = rect(-1280, 0, 0, 1024);
+        let engine = Engine::new(two_monitors());
+        let window = engine.backend().add(FakeWindow::new("clock", "Clock", 42));
+        engine.add_window_by_handle(window, WINDOWED).unwrap();
+
+        engine.move_wallpaper(window, rect(-1000, 10, -500, 310)).unwrap();
+        assert_eq!(engine.backend().window(window).rect, rect(-1000, 10, -500, 310));
+
+        engine.make_fullscreen(window, None, Scaling::Stretch).unwrap();
+        assert_eq!(engine.backend().window(window).rect, left);
+
+        engine.make_fullscreen(window, Some(&MonitorSelector::Name("display1".to_owned())), Scaling::Stretch).unwrap();
+        assert_eq!(engine.backend().window(window).rect, rect(0, 0, 1920, 1080));
+    }
+
+    #[test]
+    fn add_by_handle_covers_chosen_monitor() {
+        let engine = Engine::new(two_monitors());
+        let window = engine.backend().add(FakeWindow::new("video.mp4 - mpv", "mpv", 42));
+        let properties = WallpaperProperties {
+            fullscreen: true, monitor: Some(MonitorSelector::Index(1)), ..WallpaperProperties::default()
         };
 
-        let mut handle = null_mut();
-        for _attempt in 1..=attempts {
-            handle = match selector {
-                WindowSelector::None => unsafe { find_window_by_pid(process_id) },
-                WindowSelector::WindowTitle(title) => {
-                    let windows = list_windows();
-                    *windows.iter().find(|&&hwnd| get_window_name(hwnd) == title).unwrap_or(&null_mut())
-                },
+        engine.add_window_by_handle(window, properties).unwrap();
+
+        assert_eq!(engine.backend().window(window).rect, rect(-1280, 0, 0, 1024));
+    }
+
+    #[test]
+    fn add_by_handle_moves_window_to_chosen_monitor() {
+        let engine = Engine::new(two_monitors());
+        let window = engine.backend().add(FakeWindow::new("clock", "Clock", 42));
+        let monitor = Some(MonitorSelector::Name("DISPLAY2".to_owned()));
+        let properties = WallpaperProperties { fullscreen: false, monitor, ..WallpaperProperties::default() };
+
+        engine.add_window_by_handle(window, properties).unwrap();
+
+        assert_eq!(engine.backend().window(window).rect, rect(-1180, 100, -540, 580));
+    }
+
+    #[test]
+    fn add_by_handle_rejects_unknown_monitor_before_attaching() {
+        let engine = Engine::new(two_monitors());
+        let window = engine.backend().add(FakeWindow::new("clock", "Clock", 42));
+
+        for monitor in &[MonitorSelector::Index(2), MonitorSelector::Name("HDMI-1".to_owned())] {
+            let properties = WallpaperProperties {
+                fullscreen: true, monitor: Some(monitor.clone()), ..WallpaperProperties::default()
             };
-            
-            if handle.is_null() {
-                std::thread::sleep(std::time::Duration::from_millis(wait_for));
-            } else {
-                break;
+            match engine.add_window_by_handle(window, properties) {
+                Err(WallpaperError::MonitorNotFound) => {},
+                result => panic!("Unexpected result: {:?}", result),
             }
         }
+        assert!(engine.list_active().unwrap().is_empty());
+    }
+
+    #[test]
+    fn add_by_handle_spans_all_monitors() {
+        let engine = Engine::new(two_monitors());
+        let window = engine.backend().add(FakeWindow::new("panorama", "mpv", 42));
+        let properties = WallpaperProperties {
+            fullscreen: true, monitor: Some(MonitorSelector::Index(1)), span: true, ..WallpaperProperties::default()
+        };
+
+        engine.add_window_by_handle(window, properties).unwrap();
+
+        assert_eq!(engine.backend().window(window).rect, rect(-1280, 0, 1920, 1080));
+    }
+
+    #[test]
+    fn add_by_handle_fits_window_keeping_aspect_ratio() {
+        let engine = Engine::new(FakeDesktop::new());
+        let window = engine.backend().add(FakeWindow::new("video.mp4 - mpv", "mpv", 42));
+
+        engine.add_window_by_handle(window, WallpaperProperties { scaling: Scaling::Fit, ..FULLSCREEN }).unwrap();
+
+        assert_eq!(engine.backend().window(window).rect, rect(240, 0, 1680, 1080));
+    }
+
+    #[test]
+    fn rescaling_uses_size_from_before_attaching() {
+        let engine = Engine::new(two_monitors());
+        let window = engine.backend().add(FakeWindow::new("video.mp4 - mpv", "mpv", 42));
+        engine.add_window_by_handle(window, FULLSCREEN).unwrap();
+        assert_eq!(engine.backend().window(window).rect, rect(0, 0, 1920, 1080));
+
+        engine.make_fullscreen(window, None, Scaling::Fit).unwrap();
+        assert_eq!(engine.backend().window(window).rect, rect(240, 0, 1680, 1080));
+
+        engine.span_monitors(window, Scaling::Fit).unwrap();
+        assert_eq!(engine.backend().window(window).rect, rect(-400, 0, 1040, 1080));
+    }
+
+    #[test]
+    fn span_monitors_covers_all_monitors() {
+        let engine = Engine::new(two_monitors());
+        let window = engine.backend().add(FakeWindow::new("panorama", "mpv", 42));
+        engine.add_window_by_handle(window, WINDOWED).unwrap();
+
+        engine.span_monitors(window, Scaling::Stretch).unwrap();
+
+        assert_eq!(engine.backend().window(window).rect, rect(-1280, 0, 1920, 1080));
+    }
+
+    #[test]
+    fn add_by_handle_anchors_window_in_work_area() {
+        let mut display2 = monitor("DISPLAY2", false, rect(-1280, 0, 0, 1024));
+        display2.work_area = rect(-1280, 0, 0, 984);
+        let engine = Engine::new(FakeDesktop::with_monitors(vec![
+            monitor("DISPLAY1", true, rect(0, 0, 1920, 1080)),
+            display2,
+        ]));
+        let window = engine.backend().add(FakeWindow::new("clock", "Clock", 42));
+        let properties = WallpaperProperties {
+            monitor: Some(MonitorSelector::Index(1)),
+            placement: Some(Placement::Anchored {
+                width: 200, height: 100, anchor: Anchor::BottomRight, margins: Margins::uniform(10),
+            }),
+            work_area: true,
+            ..WallpaperProperties::default()
+        };
+
+        engine.add_window_by_handle(window, properties).unwrap();
+
+        assert_eq!(engine.backend().window(window).rect, rect(-210, 874, -10, 974));
+    }
+
+    #[test]
+    fn add_by_handle_puts_window_at_rect_on_monitor() {
+        let engine = Engine::new(two_monitors());
+        let window = engine.backend().add(FakeWindow::new("clock", "Clock", 42));
+        let properties = WallpaperProperties {
+            monitor: Some(MonitorSelector::Index(1)),
+            placement: Some(Placement::Rect(rect(20, 20, 420, 220))),
+            ..WallpaperProperties::default()
+        };
+
+        engine.add_window_by_handle(window, properties).unwrap();
+
+        assert_eq!(engine.backend().window(window).rect, rect(-1260, 20, -860, 220));
+    }
+
+    /// Primary 1920x1080 monitor at 100% scale with a 2880x1620 one at 150% to the right of it.
+    fn mixed_dpi() -> FakeDesktop {
+        let mut hidpi = monitor("HIDPI", false, rect(1920, 0, 4800, 1620));
+        hidpi.dpi = 144;
+        FakeDesktop::with_monitors(vec![monitor("DISPLAY1", true, rect(0, 0, 1920, 1080)), hidpi])
+    }
+
+    #[test]
+    fn add_by_handle_scales_placement_by_monitor_dpi() {
+        let engine = Engine::new(mixed_dpi());
+        let window = engine.backend().add(FakeWindow::new("clock", "Clock", 42));
+        let properties = WallpaperProperties {
+            monitor: Some(MonitorSelector::Index(1)),
+            placement: Some(Placement::Anchored {
+                width: 200, height: 100, anchor: Anchor::BottomRight, margins: Margins::uniform(10),
+            }),
+            ..WallpaperProperties::default()
+        };
+
+        engine.add_window_by_handle(window, properties).unwrap();
+
+        assert_eq!(engine.backend().window(window).rect, rect(4485, 1455, 4785, 1605));
+    }
+
+    #[test]
+    fn moving_between_monitors_keeps_logical_size() {
+        let engine = Engine::new(mixed_dpi());
+        let window = engine.backend().add(FakeWindow::new("clock", "Clock", 42));
+        let properties = WallpaperProperties { monitor: Some(MonitorSelector::Index(1)), ..WINDOWED };
+
+        engine.add_window_by_handle(window, properties).unwrap();
+        assert_eq!(engine.backend().window(window).rect, rect(2070, 150, 3030, 870));
+
+        engine.make_fullscreen(window, None, Scaling::Center).unwrap();
+        assert_eq!(engine.backend().window(window).rect, rect(2880, 450, 3840, 1170));
+
+        engine.make_fullscreen(window, Some(&MonitorSelector::Index(0)), Scaling::Center).unwrap();
+        assert_eq!(engine.backend().window(window).rect, rect(640, 300, 1280, 780));
+    }
+
+    #[test]
+    fn check_displays_moves_wallpapers_off_removed_monitor() {
+        let engine = Engine::new(two_monitors());
+        let video = engine.backend().add(FakeWindow::new("video.mp4 - mpv", "mpv", 42).at(rect(-1000, 100, -500, 400)));
+        let clock = engine.backend().add(FakeWindow::new("clock", "Clock", 43));
+        engine.add_window_by_handle(video, FULLSCREEN).unwrap();
+        engine.add_window_by_handle(clock, WallpaperProperties {
+            monitor: Some(MonitorSelector::Index(1)),
+            placement: Some(Placement::Anchored {
+                width: 200, height: 100, anchor: Anchor::BottomRight, margins: Margins::uniform(10),
+            }),
+            ..WallpaperProperties::default()
+        }).unwrap();
+
+        engine.backend().set_monitors(vec![monitor("DISPLAY1", true, rect(0, 0, 1920, 1080))]);
+        engine.check_displays().unwrap();
+
+        assert_eq!(engine.backend().window(video).rect, rect(0, 0, 1920, 1080));
+        assert_eq!(engine.backend().window(clock).rect, rect(1710, 970, 1910, 1070));
+        assert_eq!(engine.take_events(), [EngineEvent::DisplaysChanged { moved: vec![video, clock] }]);
+    }
+
+    #[test]
+    fn check_displays_follows_resolution_and_new_monitors() {
+        let engine = Engine::new(two_monitors());
+        let video = engine.backend().add(FakeWindow::new("video.mp4 - mpv", "mpv", 42));
+        let panorama = engine.backend().add(FakeWindow::new("panorama", "mpv", 43));
+        engine.add_window_by_handle(video, FULLSCREEN).unwrap();
+        engine.add_window_by_handle(panorama, WallpaperProperties { span: true, ..FULLSCREEN }).unwrap();
+
+        engine.backend().set_monitors(vec![
+            monitor("DISPLAY1", true, rect(0, 0, 2560, 1440)),
+            monitor("DISPLAY2", false, rect(-1280, 0, 0, 1024)),
+            monitor("DISPLAY3", false, rect(2560, 0, 4480, 1080)),
+        ]);
+        engine.check_displays().unwrap();
+
+        assert_eq!(engine.backend().window(video).rect, rect(0, 0, 2560, 1440));
+        assert_eq!(engine.backend().window(panorama).rect, rect(-1280, 0, 4480, 1440));
+
+        engine.take_events();
+        engine.check_displays().unwrap();
+        assert!(engine.take_events().is_empty());
+        assert_eq!(engine.backend().layer_resizes(), 1);
+    }
+
+    #[test]
+    fn check_displays_stays_quiet_when_nothing_moved() {
+        let engine = Engine::new(two_monitors());
+        let video = engine.backend().add(FakeWindow::new("video.mp4 - mpv", "mpv", 42));
+        engine.add_window_by_handle(video, FULLSCREEN).unwrap();
+
+        // a new monitor right of the primary one doesn't affect wallpapers on it
+        engine.backend().set_monitors(vec![
+            monitor("DISPLAY1", true, rect(0, 0, 1920, 1080)),
+            monitor("DISPLAY2", false, rect(-1280, 0, 0, 1024)),
+            monitor("DISPLAY3", false, rect(1920, 0, 3840, 1080)),
+        ]);
+        engine.check_displays().unwrap();
+
+        assert_eq!(engine.backend().window(video).rect, rect(0, 0, 1920, 1080));
+        assert_eq!(engine.backend().layer_resizes(), 1);
+        assert!(engine.take_events().is_empty());
+    }
+
+    #[test]
+    fn operations_keep_wallpapers_in_place_when_layer_grows() {
+        let engine = Engine::new(FakeDesktop::new());
+        let window = engine.backend().add(FakeWindow::new("clock", "Clock", 42));
+        engine.add_window_by_handle(window, WINDOWED).unwrap();
+        let before = engine.backend().window(window).rect;
+
+        engine.backend().set_monitors(vec![
+            monitor("DISPLAY1", true, rect(0, 0, 1920, 1080)),
+            monitor("DISPLAY2", false, rect(-1280, 0, 0, 1024)),
+        ]);
+        let other = engine.backend().add(FakeWindow::new("notes", "Notepad", 43));
+        engine.add_window_by_handle(other, WINDOWED).unwrap();
+
+        assert_eq!(engine.backend().window(window).rect, before);
+        assert_eq!(engine.take_events(), [EngineEvent::DisplaysChanged { moved: vec![window] }]);
+    }
+
+    #[test]
+    fn placement_resolves_relative_to_area() {
+        let area = rect(-1280, 0, 0, 1024);
+        let anchored = Placement::Anchored {
+            width: 200, height: 100, anchor: Anchor::Right, margins: Margins { right: 30, ..Margins::default() },
+        };
+
+        assert_eq!(Placement::Rect(rect(20, 20, 420, 220)).resolve(area, DEFAULT_DPI), rect(-1260, 20, -860, 220));
+        assert_eq!(anchored.resolve(area, DEFAULT_DPI), rect(-230, 462, -30, 562));
+    }
+
+    #[test]
+    fn placement_parses_from_config() {
+        let properties: WallpaperProperties = serde_json::from_str(r#"{
+            "fullscreen": false,
+            "placement": {"anchored": {"width": 300, "height": 120, "anchor": "topRight", "margins": {"top": 40}}},
+            "workArea": true
+        }"#).unwrap();
+
+        assert_eq!(properties.placement, Some(Placement::Anchored {
+            width: 300, height: 120, anchor: Anchor::TopRight, margins: Margins { top: 40, ..Margins::default() },
+        }));
+        assert!(properties.work_area);
+    }
+
+    #[test]
+    fn monitor_selector_parses_index_or_name() {
+        assert_eq!(MonitorSelector::from("1"), MonitorSelector::Index(1));
+        assert_eq!(MonitorSelector::from("HDMI-1"), MonitorSelector::Name("HDMI-1".to_owned()));
+        assert_eq!(serde_json::from_str::<MonitorSelector>("0").unwrap(), MonitorSelector::Index(0));
+        assert_eq!(
+            serde_json::from_str::<MonitorSelector>(r#""\\\\.\\DISPLAY2""#).unwrap(),
+            MonitorSelector::Name(r"\\.\DISPLAY2".to_owned()),
+        );
+    }
+
+    #[test]
+    fn detach_keeps_window_open() {
+        let engine = Engine::new(FakeDesktop::new());
+        let window = engine.backend().add(FakeWindow::new("clock", "Clock", 42));
+        let before = engine.backend().window(window);
+        engine.add_window_by_handle(window, FULLSCREEN).unwrap();
+
+        engine.detach_wallpaper(window).unwrap();
+
+        assert!(engine.list_active().unwrap().is_empty());
+        assert_eq!(engine.backend().window(window), before);
+        assert!(engine.backend().closed().is_empty());
+
+        match engine.detach_wallpaper(window) {
+            Err(WallpaperError::NotAttached) => {},
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn restore_all_restores_original_state() {
+        let engine = Engine::new(FakeDesktop::new());
+        let hidden = engine.backend().add(FakeWindow::new("clock", "Clock", 42).hidden());
+        let moved = engine.backend().add(FakeWindow::new("video.mp4 - mpv", "mpv", 43).at(rect(10, 20, 330, 260)));
+        let before = (engine.backend().window(hidden), engine.backend().window(moved));
+
+        engine.add_window_by_handle(hidden, WINDOWED).unwrap();
+        engine.add_window_by_handle(moved, FULLSCREEN).unwrap();
+        engine.restore_all().unwrap();
 
-        if handle.is_null() {
-            eprintln!("Cannot find handle using selector: {:?}", selector);
-            return false;
+        assert!(engine.list_active().unwrap().is_empty());
+        assert_eq!((engine.backend().window(hidden), engine.backend().window(moved)), before);
+        assert!(engine.backend().closed().is_empty());
+    }
+
+    #[test]
+    fn restore_all_skips_closed_windows() {
+        let engine = Engine::new(FakeDesktop::new());
+        let closed = engine.backend().add(FakeWindow::new("clock", "Clock", 42));
+        let window = engine.backend().add(FakeWindow::new("notes", "Notepad", 43));
+        engine.add_window_by_handle(closed, WINDOWED).unwrap();
+        engine.add_window_by_handle(window, WINDOWED).unwrap();
+        engine.backend().close_window(closed);
+
+        engine.restore_all().unwrap();
+
+        assert_eq!(engine.backend().window(window).parent, None);
+    }
+
+    #[test]
+    fn recover_layer_does_nothing_while_layer_is_valid() {
+        let engine = Engine::new(FakeDesktop::new());
+        let window = engine.backend().add(FakeWindow::new("clock", "Clock", 42));
+        engine.add_window_by_handle(window, WINDOWED).unwrap();
+
+        engine.recover_layer().unwrap();
+
+        assert_eq!(engine.backend().layer_changes(), 0);
+        assert_eq!(engine.list_active().unwrap(), [window]);
+        assert!(engine.take_events().is_empty());
+    }
+
+    #[test]
+    fn operations_reacquire_destroyed_layer() {
+        let engine = Engine::new(FakeDesktop::new());
+        let old_layer = engine.backend().layer();
+        let destroyed = engine.backend().add(FakeWindow::new("clock", "Clock", 42));
+        engine.add_window_by_handle(destroyed, WINDOWED).unwrap();
+        engine.backend().destroy_layer();
+
+        let window = engine.backend().add(FakeWindow::new("notes", "Notepad", 43));
+        engine.add_window_by_handle(window, WINDOWED).unwrap();
+
+        assert_ne!(engine.backend().layer(), old_layer);
+        assert_eq!(engine.backend().window(window).parent, Some(engine.backend().layer()));
+        assert_eq!(engine.take_events(), [EngineEvent::WorkerLost { destroyed: vec![destroyed] }]);
+        assert!(engine.take_events().is_empty());
+    }
+
+    #[test]
+    fn operations_move_wallpapers_out_of_abandoned_layer() {
+        let engine = Engine::new(FakeDesktop::new());
+        let window = engine.backend().add(FakeWindow::new("clock", "Clock", 42));
+        engine.add_window_by_handle(window, WINDOWED).unwrap();
+        engine.backend().abandon_layer();
+
+        assert_eq!(engine.list_active().unwrap(), [window]);
+
+        assert_eq!(engine.backend().layer_changes(), 1);
+        assert_eq!(engine.backend().window(window).parent, Some(engine.backend().layer()));
+        assert_eq!(engine.take_events(), [EngineEvent::WorkerLost { destroyed: vec![] }]);
+        engine.restore_all().unwrap();
+        assert!(engine.backend().window(window).decorated);
+    }
+
+    #[test]
+    fn list_active_returns_stacking_order() {
+        let engine = Engine::new(FakeDesktop::new());
+        let [a, b, c] = ["a", "b", "c"].map(|title| engine.backend().add(FakeWindow::new(title, "Clock", 42)));
+        for &window in &[c, a, b] {
+            engine.add_window_by_handle(window, WINDOWED).unwrap();
         }
-        
-        self.add_window_by_handle(handle, properties)
+        assert_eq!(engine.list_active().unwrap(), [c, a, b]);
+
+        engine.raise(c).unwrap();
+        assert_eq!(engine.list_active().unwrap(), [a, c, b]);
+        engine.lower(b).unwrap();
+        assert_eq!(engine.list_active().unwrap(), [a, b, c]);
+        engine.lower(a).unwrap();
+        engine.set_layer(b, 10).unwrap();
+        assert_eq!(engine.list_active().unwrap(), [a, c, b]);
+        engine.set_layer(b, 0).unwrap();
+        assert_eq!(engine.list_active().unwrap(), [b, a, c]);
+        assert_eq!(engine.backend().list_attached().unwrap(), [b, a, c]);
+    }
+
+    #[test]
+    fn recover_layer_keeps_stacking_order() {
+        let engine = Engine::new(FakeDesktop::new());
+        let [a, b] = ["a", "b"].map(|title| engine.backend().add(FakeWindow::new(title, "Clock", 42)));
+        engine.add_window_by_handle(a, WINDOWED).unwrap();
+        engine.add_window_by_handle(b, WINDOWED).unwrap();
+        engine.lower(b).unwrap();
+
+        engine.backend().abandon_layer();
+        engine.recover_layer().unwrap();
+
+        assert_eq!(engine.backend().list_attached().unwrap(), [b, a]);
+        assert_eq!(engine.list_active().unwrap(), [b, a]);
     }
 
-    pub fn remove_wallpaper(&self, hwnd: HWND) {
-        // TODO ensure that provided handle is actually attached to wallpaper window
-        use winapi::um::winuser::{InvalidateRect, SendMessageW, WM_CLOSE};
-        unsafe { 
-            remove_window_from_wallpaper(self.worker, hwnd);
-            std::thread::sleep(std::time::Duration::from_millis(32));
-            SendMessageW(hwnd, WM_CLOSE, 0, 0);
-            std::thread::sleep(std::time::Duration::from_millis(32));
-            InvalidateRect(null_mut(), null_mut(), 1);
+    #[test]
+    fn add_by_handle_rejects_missing_windows() {
+        let engine = Engine::new(FakeDesktop::new());
+        let window = engine.backend().add(FakeWindow::new("clock", "Clock", 42));
+        engine.backend().close_window(window);
+
+        match engine.add_window_by_handle(window, WINDOWED) {
+            Err(WallpaperError::WindowNotFound) => {},
+            result => panic!("Unexpected result: {:?}", result),
         }
     }
 
+    #[test]
+    fn remove_rejects_regular_windows() {
+        let engine = Engine::new(FakeDesktop::new());
+        let window = engine.backend().add(FakeWindow::new("clock", "Clock", 42));
+
+        match engine.remove_wallpaper(window) {
+            Err(WallpaperError::NotAttached) => {},
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert!(engine.backend().exists(window));
+    }
 }